@@ -0,0 +1,68 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Length of the truncated HMAC-SHA256 appended to packets when a key is
+/// configured
+pub const MAC_LENGTH: usize = 16;
+
+/// Pre-shared key used to authenticate packets
+#[derive(Clone)]
+pub struct AuthKey(Hmac<Sha256>);
+
+impl AuthKey {
+    pub fn new(key: &[u8]) -> Self {
+        AuthKey(Hmac::new_from_slice(key).expect("hmac accepts keys of any length"))
+    }
+
+    pub fn sign(&self, frame: &[u8]) -> [u8; MAC_LENGTH] {
+        let mut hmac = self.0.clone();
+        hmac.update(frame);
+
+        let code = hmac.finalize().into_bytes();
+
+        let mut mac = [0u8; MAC_LENGTH];
+        mac.copy_from_slice(&code[0..MAC_LENGTH]);
+        mac
+    }
+
+    pub fn verify(&self, frame: &[u8], mac: &[u8]) -> bool {
+        let mut hmac = self.0.clone();
+        hmac.update(frame);
+
+        // constant time comparison
+        hmac.verify_truncated_left(mac).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_mac() {
+        let key = AuthKey::new(b"key");
+        let mac = key.sign(b"frame");
+        assert!(key.verify(b"frame", &mac));
+    }
+
+    #[test]
+    fn rejects_tampered_mac() {
+        let key = AuthKey::new(b"key");
+        let mut mac = key.sign(b"frame");
+        mac[0] ^= 1;
+        assert!(!key.verify(b"frame", &mac));
+    }
+
+    #[test]
+    fn rejects_tampered_frame() {
+        let key = AuthKey::new(b"key");
+        let mac = key.sign(b"frame");
+        assert!(!key.verify(b"frams", &mac));
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        let mac = AuthKey::new(b"key").sign(b"frame");
+        assert!(!AuthKey::new(b"other").verify(b"frame", &mac));
+    }
+}
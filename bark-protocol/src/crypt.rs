@@ -0,0 +1,101 @@
+use chacha20poly1305::{AeadInPlace, KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::types::SessionId;
+
+/// Length of the authentication tag following encrypted data
+pub const TAG_LENGTH: usize = 16;
+
+// label used to derive the encryption key from the pre-shared key, keeping
+// it distinct from the key used for packet authentication
+const DERIVE_LABEL: &[u8] = b"bark audio encryption";
+
+#[derive(Debug)]
+pub struct CryptError;
+
+/// Key used to encrypt and decrypt audio payloads
+#[derive(Clone)]
+pub struct CipherKey(XChaCha20Poly1305);
+
+impl CipherKey {
+    /// Derives a cipher key from a pre-shared key of any length
+    pub fn derive(key: &[u8]) -> Self {
+        let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+            .expect("hmac accepts keys of any length");
+
+        hmac.update(DERIVE_LABEL);
+
+        let key = hmac.finalize().into_bytes();
+        CipherKey(XChaCha20Poly1305::new(&key))
+    }
+
+    pub fn encrypt(&self, sid: SessionId, seq: u64, aad: &[u8], data: &mut [u8]) -> [u8; TAG_LENGTH] {
+        let tag = self.0.encrypt_in_place_detached(&nonce(sid, seq), aad, data)
+            .expect("encrypt audio packet");
+
+        tag.into()
+    }
+
+    pub fn decrypt(&self, sid: SessionId, seq: u64, aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), CryptError> {
+        if tag.len() != TAG_LENGTH {
+            return Err(CryptError);
+        }
+
+        self.0.decrypt_in_place_detached(&nonce(sid, seq), aad, data, Tag::from_slice(tag))
+            .map_err(|_| CryptError)
+    }
+}
+
+// every audio packet in a stream has a unique seq, and every stream has a
+// unique sid, so a nonce made of the two is never reused with new data
+fn nonce(sid: SessionId, seq: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[0..8].copy_from_slice(&sid.0.to_le_bytes());
+    nonce[8..16].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(key: &CipherKey, sid: SessionId, seq: u64) -> ([u8; 5], [u8; TAG_LENGTH]) {
+        let mut data = *b"audio";
+        let tag = key.encrypt(sid, seq, b"header", &mut data);
+        (data, tag)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = CipherKey::derive(b"key");
+        let (mut data, tag) = encrypt(&key, SessionId(1), 2);
+
+        assert_ne!(&data, b"audio");
+        assert!(key.decrypt(SessionId(1), 2, b"header", &mut data, &tag).is_ok());
+        assert_eq!(&data, b"audio");
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let (mut data, tag) = encrypt(&CipherKey::derive(b"key"), SessionId(1), 2);
+        let other = CipherKey::derive(b"other");
+        assert!(other.decrypt(SessionId(1), 2, b"header", &mut data, &tag).is_err());
+    }
+
+    #[test]
+    fn wrong_nonce_fails() {
+        let key = CipherKey::derive(b"key");
+        let (data, tag) = encrypt(&key, SessionId(1), 2);
+
+        assert!(key.decrypt(SessionId(1), 3, b"header", &mut data.clone(), &tag).is_err());
+        assert!(key.decrypt(SessionId(2), 2, b"header", &mut data.clone(), &tag).is_err());
+    }
+
+    #[test]
+    fn tampered_header_fails() {
+        let key = CipherKey::derive(b"key");
+        let (mut data, tag) = encrypt(&key, SessionId(1), 2);
+        assert!(key.decrypt(SessionId(1), 2, b"heaper", &mut data, &tag).is_err());
+    }
+}
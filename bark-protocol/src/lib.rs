@@ -1,21 +1,64 @@
 #![no_std]
 
+use bytemuck::{Pod, Zeroable};
+
+pub mod auth;
 pub mod buffer;
+pub mod crypt;
 pub mod packet;
 pub mod time;
 pub mod types;
 
-pub const SAMPLE_RATE: SampleRate = SampleRate(48000);
-pub const CHANNELS: ChannelCount = ChannelCount(2);
+/// Version of the bark protocol this crate implements. Nodes predating
+/// protocol versioning are treated as version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 pub const FRAMES_PER_PACKET: usize = 120; // 2.5ms at 48khz, compatible with opus
-pub const SAMPLES_PER_PACKET: usize = CHANNELS.0 as usize * FRAMES_PER_PACKET;
+pub const MAX_CHANNELS: ChannelCount = ChannelCount(8);
+pub const MAX_SAMPLES_PER_PACKET: usize = MAX_CHANNELS.0 as usize * FRAMES_PER_PACKET;
 
-#[derive(Copy, Clone, Debug)]
+pub const MIN_SAMPLE_RATE: SampleRate = SampleRate(8000);
+pub const MAX_SAMPLE_RATE: SampleRate = SampleRate(192000);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Zeroable, Pod)]
+#[repr(transparent)]
 pub struct SampleRate(pub u32);
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Zeroable, Pod)]
+#[repr(transparent)]
 pub struct ChannelCount(pub u16);
 
+/// Sample rate and channel count of an audio stream, advertised by the
+/// sender in every audio packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Zeroable, Pod)]
+#[repr(C)]
+pub struct StreamFormat {
+    pub sample_rate: SampleRate,
+    pub channels: ChannelCount,
+    _pad: u16,
+}
+
+impl StreamFormat {
+    pub const DEFAULT: StreamFormat = StreamFormat::new(SampleRate(48000), ChannelCount(2));
+
+    pub const fn new(sample_rate: SampleRate, channels: ChannelCount) -> Self {
+        StreamFormat { sample_rate, channels, _pad: 0 }
+    }
+
+    /// Whether this format is within the limits the protocol supports
+    pub fn is_valid(&self) -> bool {
+        let rate = self.sample_rate.0;
+        let channels = self.channels.0;
+
+        (MIN_SAMPLE_RATE.0..=MAX_SAMPLE_RATE.0).contains(&rate) &&
+            (1..=MAX_CHANNELS.0).contains(&channels)
+    }
+
+    pub const fn samples_per_packet(&self) -> usize {
+        self.channels.0 as usize * FRAMES_PER_PACKET
+    }
+}
+
 impl From<SampleRate> for usize {
     fn from(value: SampleRate) -> Self {
         value.0.try_into().expect("SampleRate -> usize")
@@ -24,7 +67,7 @@ impl From<SampleRate> for usize {
 
 impl From<SampleRate> for u32 {
     fn from(value: SampleRate) -> Self {
-        value.0.into()
+        value.0
     }
 }
 
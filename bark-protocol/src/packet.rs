@@ -4,26 +4,49 @@ use core::ops::Range;
 
 use bytemuck::Zeroable;
 
+use crate::auth::{self, AuthKey};
 use crate::buffer::{AllocError, PacketBuffer};
+use crate::crypt::{self, CipherKey};
 use crate::types::stats::node::NodeStats;
-use crate::types::stats::receiver::ReceiverStats;
-use crate::types::{self, AudioPacketHeader, Magic, SessionId, StatsReplyFlags};
+use crate::types::stats::receiver::{ReceiverDiagnostics, ReceiverStats};
+use crate::types::{self, AudioPacketHeader, FecPacketHeader, Magic, ProtocolInfo, ReceiverId, SampleFormat, SessionId, StatsReplyFlags};
 use crate::time::SampleDuration;
+use crate::StreamFormat;
 
-pub const MAX_PACKET_SIZE: usize =
+pub const MAX_AUDIO_PACKET_SIZE: usize =
     size_of::<types::PacketHeader>() +
     size_of::<types::AudioPacketHeader>() +
     size_of::<types::AudioPacketBuffer>();
 
+// fec packets carry parity data the size of an entire audio packet, making
+// them the largest packet type. any packet may be followed by an
+// authentication code
+pub const MAX_PACKET_SIZE: usize =
+    size_of::<types::PacketHeader>() +
+    size_of::<types::FecPacketHeader>() +
+    MAX_AUDIO_PACKET_SIZE +
+    auth::MAC_LENGTH;
+
+/// Maximum number of audio packets a single fec packet can cover
+pub const MAX_FEC_GROUP_SIZE: u32 = 32;
+
 #[derive(Debug)]
-pub struct Packet(PacketBuffer);
+pub struct Packet {
+    buffer: PacketBuffer,
+    // length of the authentication code following the packet in the
+    // buffer, zero unless the packet was authenticated on receipt
+    trailer: usize,
+    // whether the packet was rewritten on receipt, and so no longer has
+    // the exact bytes the sender sent
+    rewritten: bool,
+}
 
 impl Packet {
     fn allocate(magic: Magic, len: usize) -> Result<Self, AllocError> {
         let header_size = size_of::<types::PacketHeader>();
         let packet_len = header_size + len;
 
-        let mut packet = Packet(PacketBuffer::allocate(packet_len)?);
+        let mut packet = Packet { buffer: PacketBuffer::allocate(packet_len)?, trailer: 0, rewritten: false };
         packet.header_mut().magic = magic;
         Ok(packet)
     }
@@ -33,17 +56,56 @@ impl Packet {
         if buffer.len() < header_size {
             None
         } else {
-            Some(Packet(buffer))
+            Some(Packet { buffer, trailer: 0, rewritten: false })
         }
     }
 
     pub fn as_buffer(&self) -> &PacketBuffer {
-        &self.0
+        &self.buffer
+    }
+
+    /// Packet header and data, excluding any authentication code. This is
+    /// what gets signed when sending with a key
+    pub fn frame(&self) -> &[u8] {
+        let bytes = self.buffer.as_bytes();
+        &bytes[0..(bytes.len() - self.trailer)]
+    }
+
+    /// Whether the packet was rewritten on receipt to read it, such as an
+    /// audio packet from a node with a different length audio header.
+    /// `frame` of a rewritten packet is not what the sender sent.
+    pub fn is_rewritten(&self) -> bool {
+        self.rewritten
     }
 
-    pub fn parse(self) -> Option<PacketKind> {
+    /// Parses the packet. If a key is given, packets without a valid
+    /// authentication code are rejected
+    pub fn parse(mut self, auth: Option<&AuthKey>) -> Option<PacketKind> {
+        if let Some(key) = auth {
+            let header_size = size_of::<types::PacketHeader>();
+            let frame_len = self.buffer.len().checked_sub(auth::MAC_LENGTH)?;
+
+            if frame_len < header_size {
+                return None;
+            }
+
+            let (frame, mac) = self.buffer.as_bytes().split_at(frame_len);
+            if !key.verify(frame, mac) {
+                return None;
+            }
+
+            self.trailer = auth::MAC_LENGTH;
+        }
+
         match self.header().magic {
             Magic::AUDIO => Audio::parse(self).map(PacketKind::Audio),
+            Magic::AUDIO_OPUS => AudioOpus::parse(self).map(PacketKind::AudioOpus),
+            Magic::AUDIO_FEC => AudioFec::parse(self).map(PacketKind::AudioFec),
+            Magic::AUDIO_ENCRYPTED => AudioEncrypted::parse(self).map(PacketKind::AudioEncrypted),
+            Magic::NACK => Nack::parse(self).map(PacketKind::Nack),
+            Magic::GOODBYE => Goodbye::parse(self).map(PacketKind::Goodbye),
+            Magic::METADATA => Metadata::parse(self).map(PacketKind::Metadata),
+            Magic::CONTROL => Control::parse(self).map(PacketKind::Control),
             Magic::TIME => Time::parse(self).map(PacketKind::Time),
             Magic::STATS_REQ => StatsRequest::parse(self).map(PacketKind::StatsRequest),
             Magic::STATS_REPLY => StatsReply::parse(self).map(PacketKind::StatsReply),
@@ -53,35 +115,89 @@ impl Packet {
 
     pub fn header(&self) -> &types::PacketHeader {
         let header_size = size_of::<types::PacketHeader>();
-        let header_bytes = &self.0.as_bytes()[0..header_size];
+        let header_bytes = &self.buffer.as_bytes()[0..header_size];
         bytemuck::from_bytes(header_bytes)
     }
 
     pub fn header_mut(&mut self) -> &mut types::PacketHeader {
         let header_size = size_of::<types::PacketHeader>();
-        let header_bytes = &mut self.0.as_bytes_mut()[0..header_size];
+        let header_bytes = &mut self.buffer.as_bytes_mut()[0..header_size];
         bytemuck::from_bytes_mut(header_bytes)
     }
 
     pub fn len(&self) -> usize {
         let header_size = size_of::<types::PacketHeader>();
-        self.0.len() - header_size
+        self.buffer.len() - header_size - self.trailer
     }
 
     pub fn as_bytes(&self) -> &[u8] {
         let header_size = size_of::<types::PacketHeader>();
-        &self.0.as_bytes()[header_size..]
+        let end = self.buffer.len() - self.trailer;
+        &self.buffer.as_bytes()[header_size..end]
     }
 
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let header_size = size_of::<types::PacketHeader>();
-        &mut self.0.as_bytes_mut()[header_size..]
+        let end = self.buffer.len() - self.trailer;
+        &mut self.buffer.as_bytes_mut()[header_size..end]
+    }
+}
+
+/// Reads an audio header of the given length from the start of `bytes`.
+/// Fields missing from shorter headers are zeroed, fields we don't know
+/// about in longer headers are ignored
+fn read_audio_header(bytes: &[u8], length: usize) -> AudioPacketHeader {
+    let mut header = AudioPacketHeader::zeroed();
+    let copy = cmp::min(length, AudioPacketHeader::LENGTH);
+    bytemuck::bytes_of_mut(&mut header)[0..copy].copy_from_slice(&bytes[0..copy]);
+
+    if length == AudioPacketHeader::LEGACY_LENGTH {
+        // legacy nodes only ever sent the default format
+        header.format = StreamFormat::DEFAULT;
+    }
+
+    header
+}
+
+/// Rewrites an audio packet with a header of a different length to ours
+/// so that it can be read in place. Returns `None` if the packet is too
+/// short to hold the header it claims to have
+fn normalize_audio_header(packet: Packet) -> Option<Packet> {
+    let flags = packet.header().flags;
+    let length = AudioPacketHeader::length_from_flags(flags);
+
+    if packet.len() < length {
+        return None;
     }
+
+    if length == AudioPacketHeader::LENGTH {
+        return Some(packet);
+    }
+
+    let header = read_audio_header(packet.as_bytes(), length);
+    let data = &packet.as_bytes()[length..];
+
+    let mut rewritten = Packet::allocate(packet.header().magic, AudioPacketHeader::LENGTH + data.len()).ok()?;
+    rewritten.header_mut().flags = AudioPacketHeader::packet_flags(flags) | AudioPacketHeader::flags();
+    rewritten.rewritten = true;
+
+    let bytes = rewritten.as_bytes_mut();
+    bytes[0..AudioPacketHeader::LENGTH].copy_from_slice(bytemuck::bytes_of(&header));
+    bytes[AudioPacketHeader::LENGTH..].copy_from_slice(data);
+
+    Some(rewritten)
 }
 
 #[derive(Debug)]
 pub enum PacketKind {
     Audio(Audio),
+    AudioOpus(AudioOpus),
+    AudioFec(AudioFec),
+    AudioEncrypted(AudioEncrypted),
+    Nack(Nack),
+    Goodbye(Goodbye),
+    Metadata(Metadata),
+    Control(Control),
     Time(Time),
     StatsRequest(StatsRequest),
     StatsReply(StatsReply),
@@ -91,56 +207,94 @@ pub enum PacketKind {
 pub struct Audio(Packet);
 
 impl Audio {
-    const LENGTH: usize =
-        size_of::<types::AudioPacketHeader>() +
-        size_of::<types::AudioPacketBuffer>();
+    const HEADER_LENGTH: usize = size_of::<types::AudioPacketHeader>();
+
+    const fn length(stream: StreamFormat, format: SampleFormat) -> usize {
+        Self::HEADER_LENGTH + stream.samples_per_packet() * format.bytes_per_sample()
+    }
 
-    pub fn write() -> Result<AudioWriter, AllocError> {
-        let packet = Packet::allocate(Magic::AUDIO, Self::LENGTH)?;
+    pub fn write(stream: StreamFormat) -> Result<AudioWriter, AllocError> {
+        let mut packet = Packet::allocate(Magic::AUDIO, Self::length(stream, SampleFormat::F32))?;
+        packet.header_mut().flags = SampleFormat::F32.into_flags() | AudioPacketHeader::flags();
 
         Ok(AudioWriter {
             packet: Audio(packet),
+            stream,
             written: SampleDuration::zero(),
         })
     }
 
+    /// Allocates a zeroed audio packet in the given sample format, for
+    /// callers converting audio between formats. The length of the packet
+    /// is determined by the stream format in the header.
+    pub fn with_format(format: SampleFormat, header: AudioPacketHeader) -> Result<Self, AllocError> {
+        let mut packet = Packet::allocate(Magic::AUDIO, Self::length(header.format, format))?;
+        packet.header_mut().flags = format.into_flags() | AudioPacketHeader::flags();
+
+        let mut audio = Audio(packet);
+        *audio.header_mut() = header;
+        Ok(audio)
+    }
+
     pub fn parse(packet: Packet) -> Option<Self> {
-        if packet.len() != Self::LENGTH {
+        let format = SampleFormat::from_flags(AudioPacketHeader::packet_flags(packet.header().flags))?;
+        let packet = normalize_audio_header(packet)?;
+
+        let audio = Audio(packet);
+        let stream = audio.header().format;
+
+        if !stream.is_valid() {
             return None;
         }
 
-        if packet.header().flags != 0 {
+        if audio.0.len() != Self::length(stream, format) {
             return None;
         }
 
-        Some(Audio(packet))
+        Some(audio)
     }
 
     pub fn as_packet(&self) -> &Packet {
         &self.0
     }
 
+    pub fn format(&self) -> SampleFormat {
+        // validated in parse, or set by us on construction
+        SampleFormat::from_flags(AudioPacketHeader::packet_flags(self.0.header().flags))
+            .expect("invalid sample format in audio packet")
+    }
+
+    pub fn stream_format(&self) -> StreamFormat {
+        self.header().format
+    }
+
+    /// Raw sample data, encoded according to `format`
+    pub fn data(&self) -> &[u8] {
+        &self.0.as_bytes()[Self::HEADER_LENGTH..]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.0.as_bytes_mut()[Self::HEADER_LENGTH..]
+    }
+
+    /// Sample data of an f32 packet. Panics if packet is another format.
     pub fn buffer(&self) -> &[f32] {
-        let header_size = size_of::<types::AudioPacketHeader>();
-        let buffer_bytes = &self.0.as_bytes()[header_size..];
-        bytemuck::cast_slice(buffer_bytes)
+        assert!(self.format() == SampleFormat::F32);
+        bytemuck::cast_slice(self.data())
     }
 
     pub fn buffer_mut(&mut self) -> &mut [f32] {
-        let header_size = size_of::<types::AudioPacketHeader>();
-        let buffer_bytes = &mut self.0.as_bytes_mut()[header_size..];
-        bytemuck::cast_slice_mut(buffer_bytes)
+        assert!(self.format() == SampleFormat::F32);
+        bytemuck::cast_slice_mut(self.data_mut())
     }
 
     pub fn header(&self) -> &types::AudioPacketHeader {
-        let header_size = size_of::<types::AudioPacketHeader>();
-        let header_bytes = &self.0.as_bytes()[0..header_size];
+        let header_bytes = &self.0.as_bytes()[0..Self::HEADER_LENGTH];
         bytemuck::from_bytes(header_bytes)
     }
 
     pub fn header_mut(&mut self) -> &mut types::AudioPacketHeader {
-        let header_size = size_of::<types::AudioPacketHeader>();
-        let header_bytes = &mut self.0.as_bytes_mut()[0..header_size];
+        let header_bytes = &mut self.0.as_bytes_mut()[0..Self::HEADER_LENGTH];
         bytemuck::from_bytes_mut(header_bytes)
     }
 }
@@ -148,6 +302,7 @@ impl Audio {
 #[derive(Debug)]
 pub struct AudioWriter {
     packet: Audio,
+    stream: StreamFormat,
     written: SampleDuration,
 }
 
@@ -161,7 +316,7 @@ impl AudioWriter {
     }
 
     fn remaining_buffer_mut(&mut self) -> &mut [f32] {
-        let offset = self.length().as_buffer_offset();
+        let offset = self.length().as_buffer_offset(self.stream.channels);
         &mut self.packet.buffer_mut()[offset..]
     }
 
@@ -170,10 +325,10 @@ impl AudioWriter {
     }
 
     pub fn write(&mut self, audio: &[f32]) -> SampleDuration {
-        let input_duration = SampleDuration::from_buffer_offset(audio.len());
+        let input_duration = SampleDuration::from_buffer_offset(audio.len(), self.stream.channels);
         let copy_duration = cmp::min(input_duration, self.remaining());
 
-        let copy_len = copy_duration.as_buffer_offset();
+        let copy_len = copy_duration.as_buffer_offset(self.stream.channels);
         let source_buffer = &audio[0..copy_len];
         let dest_buffer = &mut self.remaining_buffer_mut()[0..copy_len];
         dest_buffer.copy_from_slice(source_buffer);
@@ -188,34 +343,467 @@ impl AudioWriter {
             panic!("into_audio_packet called on writer with invalid length");
         }
 
+        assert!(header.format == self.stream);
+
         *self.packet.header_mut() = header;
         self.packet
     }
 }
 
+#[derive(Debug)]
+pub struct AudioOpus(Packet);
+
+impl AudioOpus {
+    const HEADER_LENGTH: usize = size_of::<types::AudioPacketHeader>();
+
+    // encoded data can never be larger than the uncompressed audio, this
+    // also keeps opus packets within MAX_AUDIO_PACKET_SIZE
+    pub const MAX_DATA_LENGTH: usize = size_of::<types::AudioPacketBuffer>();
+
+    pub fn new(header: AudioPacketHeader, data: &[u8]) -> Result<Self, AllocError> {
+        assert!(data.len() <= Self::MAX_DATA_LENGTH);
+
+        let mut packet = Packet::allocate(Magic::AUDIO_OPUS, Self::HEADER_LENGTH + data.len())?;
+        packet.header_mut().flags = AudioPacketHeader::flags();
+
+        let mut opus = AudioOpus(packet);
+        *opus.header_mut() = header;
+        opus.data_mut().copy_from_slice(data);
+
+        Ok(opus)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if AudioPacketHeader::packet_flags(packet.header().flags) != 0 {
+            return None;
+        }
+
+        let packet = normalize_audio_header(packet)?;
+
+        if packet.len() > Self::HEADER_LENGTH + Self::MAX_DATA_LENGTH {
+            return None;
+        }
+
+        let opus = AudioOpus(packet);
+
+        if !opus.header().format.is_valid() {
+            return None;
+        }
+
+        Some(opus)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    /// Opus encoded data for exactly one packet's worth of audio
+    pub fn data(&self) -> &[u8] {
+        &self.0.as_bytes()[Self::HEADER_LENGTH..]
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.0.as_bytes_mut()[Self::HEADER_LENGTH..]
+    }
+
+    pub fn header(&self) -> &types::AudioPacketHeader {
+        let header_bytes = &self.0.as_bytes()[0..Self::HEADER_LENGTH];
+        bytemuck::from_bytes(header_bytes)
+    }
+
+    fn header_mut(&mut self) -> &mut types::AudioPacketHeader {
+        let header_bytes = &mut self.0.as_bytes_mut()[0..Self::HEADER_LENGTH];
+        bytemuck::from_bytes_mut(header_bytes)
+    }
+}
+
+/// Parity over a group of consecutive audio packets, allowing receivers to
+/// reconstruct any one packet lost from the group
+#[derive(Debug)]
+pub struct AudioFec(Packet);
+
+impl AudioFec {
+    const HEADER_LENGTH: usize = size_of::<types::FecPacketHeader>();
+
+    pub fn new(header: FecPacketHeader, parity: &[u8]) -> Result<Self, AllocError> {
+        assert!(parity.len() <= MAX_AUDIO_PACKET_SIZE);
+
+        let packet = Packet::allocate(Magic::AUDIO_FEC, Self::HEADER_LENGTH + parity.len())?;
+
+        let mut fec = AudioFec(packet);
+        *fec.header_mut() = header;
+        fec.parity_mut().copy_from_slice(parity);
+
+        Ok(fec)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() < Self::HEADER_LENGTH {
+            return None;
+        }
+
+        if packet.len() > Self::HEADER_LENGTH + MAX_AUDIO_PACKET_SIZE {
+            return None;
+        }
+
+        if packet.header().flags != 0 {
+            return None;
+        }
+
+        let fec = AudioFec(packet);
+
+        let count = fec.header().count;
+        if count == 0 || count > MAX_FEC_GROUP_SIZE {
+            return None;
+        }
+
+        Some(fec)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    /// XOR of all covered packets including their packet headers, each zero
+    /// padded to the length of the longest
+    pub fn parity(&self) -> &[u8] {
+        &self.0.as_bytes()[Self::HEADER_LENGTH..]
+    }
+
+    fn parity_mut(&mut self) -> &mut [u8] {
+        &mut self.0.as_bytes_mut()[Self::HEADER_LENGTH..]
+    }
+
+    pub fn header(&self) -> &types::FecPacketHeader {
+        let header_bytes = &self.0.as_bytes()[0..Self::HEADER_LENGTH];
+        bytemuck::from_bytes(header_bytes)
+    }
+
+    fn header_mut(&mut self) -> &mut types::FecPacketHeader {
+        let header_bytes = &mut self.0.as_bytes_mut()[0..Self::HEADER_LENGTH];
+        bytemuck::from_bytes_mut(header_bytes)
+    }
+}
+
+/// An audio or opus packet with its payload encrypted. The audio header is
+/// left in the clear (but authenticated) so that receivers and other
+/// sources can still follow the stream without the key
+#[derive(Debug)]
+pub struct AudioEncrypted {
+    packet: Packet,
+    // audio header as we read it, the header in the packet is left as the
+    // sender wrote it since it's authenticated along with the payload
+    header: AudioPacketHeader,
+}
+
+impl AudioEncrypted {
+    const HEADER_LENGTH: usize = size_of::<types::AudioPacketHeader>();
+
+    // the encrypted payload begins with the packet header of the inner
+    // packet, identifying its type and format
+    const INNER_HEADER_LENGTH: usize = size_of::<types::PacketHeader>();
+
+    const MIN_PAYLOAD_LENGTH: usize = Self::INNER_HEADER_LENGTH + crypt::TAG_LENGTH;
+
+    /// Encrypts an `Audio` or `AudioOpus` packet
+    pub fn encrypt(inner: &Packet, key: &CipherKey) -> Result<Self, AllocError> {
+        let magic = inner.header().magic;
+        assert!(magic == Magic::AUDIO || magic == Magic::AUDIO_OPUS);
+
+        let header: AudioPacketHeader = *bytemuck::from_bytes(&inner.as_bytes()[0..Self::HEADER_LENGTH]);
+        let data = &inner.as_bytes()[Self::HEADER_LENGTH..];
+
+        let payload_length = Self::INNER_HEADER_LENGTH + data.len();
+        let length = Self::HEADER_LENGTH + payload_length + crypt::TAG_LENGTH;
+
+        let mut packet = Packet::allocate(Magic::AUDIO_ENCRYPTED, length)?;
+        packet.header_mut().flags = AudioPacketHeader::flags();
+
+        let bytes = packet.as_bytes_mut();
+        let (header_bytes, rest) = bytes.split_at_mut(Self::HEADER_LENGTH);
+        let (payload, tag) = rest.split_at_mut(payload_length);
+
+        header_bytes.copy_from_slice(bytemuck::bytes_of(&header));
+        payload[0..Self::INNER_HEADER_LENGTH].copy_from_slice(bytemuck::bytes_of(inner.header()));
+        payload[Self::INNER_HEADER_LENGTH..].copy_from_slice(data);
+
+        tag.copy_from_slice(&key.encrypt(header.sid, header.seq, header_bytes, payload));
+
+        Ok(AudioEncrypted { packet, header })
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        let flags = packet.header().flags;
+
+        if AudioPacketHeader::packet_flags(flags) != 0 {
+            return None;
+        }
+
+        let header_length = AudioPacketHeader::length_from_flags(flags);
+
+        if packet.len() < header_length + Self::MIN_PAYLOAD_LENGTH {
+            return None;
+        }
+
+        let header = read_audio_header(packet.as_bytes(), header_length);
+
+        if !header.format.is_valid() {
+            return None;
+        }
+
+        Some(AudioEncrypted { packet, header })
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.packet
+    }
+
+    pub fn header(&self) -> &types::AudioPacketHeader {
+        &self.header
+    }
+
+    // length of the audio header as the sender wrote it
+    fn wire_header_length(&self) -> usize {
+        AudioPacketHeader::length_from_flags(self.packet.header().flags)
+    }
+
+    /// Decrypts the packet, returning the inner `Audio` or `AudioOpus`
+    /// packet. Returns `None` if the packet fails to decrypt with this key.
+    pub fn decrypt(&self, key: &CipherKey) -> Option<PacketKind> {
+        let header = *self.header();
+        let header_length = self.wire_header_length();
+
+        let bytes = self.packet.as_bytes();
+        let (header_bytes, rest) = bytes.split_at(header_length);
+        let (ciphertext, tag) = rest.split_at(rest.len() - crypt::TAG_LENGTH);
+
+        // the decrypted packet is the same length as the ciphertext plus
+        // the audio header. decrypt in place at the end of the buffer, then
+        // move the inner packet header to the front to make room for the
+        // audio header. the inner packet is parsed below, which reads the
+        // audio header as the sender wrote it, as for any audio packet:
+        let packet_header_length = Self::INNER_HEADER_LENGTH;
+        let mut buffer = PacketBuffer::allocate(header_length + ciphertext.len()).ok()?;
+        let plain = buffer.as_bytes_mut();
+
+        plain[header_length..].copy_from_slice(ciphertext);
+
+        key.decrypt(header.sid, header.seq, header_bytes, &mut plain[header_length..], tag).ok()?;
+
+        plain.copy_within(header_length..(header_length + packet_header_length), 0);
+        plain[packet_header_length..(packet_header_length + header_length)].copy_from_slice(header_bytes);
+
+        let inner = Packet::from_buffer(buffer)?;
+
+        match inner.header().magic {
+            Magic::AUDIO => Audio::parse(inner).map(PacketKind::Audio),
+            Magic::AUDIO_OPUS => AudioOpus::parse(inner).map(PacketKind::AudioOpus),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Nack(Packet);
+
+impl Nack {
+    const LENGTH: usize = size_of::<types::NackPacket>();
+
+    /// Maximum number of packets a single nack can request
+    pub const MAX_COUNT: u32 = 16;
+
+    pub fn new(sid: SessionId, seq: u64, count: u32) -> Result<Self, AllocError> {
+        assert!(count > 0 && count <= Self::MAX_COUNT);
+
+        let mut nack = Nack(Packet::allocate(Magic::NACK, Self::LENGTH)?);
+        let data = nack.data_mut();
+        data.sid = sid;
+        data.seq = seq;
+        data.count = count;
+
+        Ok(nack)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        if packet.header().flags != 0 {
+            return None;
+        }
+
+        let nack = Nack(packet);
+
+        let count = nack.data().count;
+        if count == 0 || count > Self::MAX_COUNT {
+            return None;
+        }
+
+        Some(nack)
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn data(&self) -> &types::NackPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::NackPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct Goodbye(Packet);
+
+impl Goodbye {
+    const LENGTH: usize = size_of::<types::GoodbyePacket>();
+
+    pub fn new(sid: SessionId) -> Result<Self, AllocError> {
+        let mut goodbye = Goodbye(Packet::allocate(Magic::GOODBYE, Self::LENGTH)?);
+        goodbye.data_mut().sid = sid;
+        Ok(goodbye)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        if packet.header().flags != 0 {
+            return None;
+        }
+
+        Some(Goodbye(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn data(&self) -> &types::GoodbyePacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    fn data_mut(&mut self) -> &mut types::GoodbyePacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct Metadata(Packet);
+
+impl Metadata {
+    const LENGTH: usize = size_of::<types::MetadataPacket>();
+
+    pub fn new(sid: SessionId) -> Result<Self, AllocError> {
+        let mut metadata = Metadata(Packet::allocate(Magic::METADATA, Self::LENGTH)?);
+        metadata.data_mut().sid = sid;
+        Ok(metadata)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        if packet.len() != Self::LENGTH {
+            return None;
+        }
+
+        if packet.header().flags != 0 {
+            return None;
+        }
+
+        Some(Metadata(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn data(&self) -> &types::MetadataPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    pub fn data_mut(&mut self) -> &mut types::MetadataPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
+#[derive(Debug)]
+pub struct Control(Packet);
+
+impl Control {
+    const LENGTH: usize = size_of::<types::ControlPacket>();
+
+    // length of control packets before the sent timestamp was added
+    const LEGACY_LENGTH: usize = 16;
+
+    pub fn new(rid: ReceiverId) -> Result<Self, AllocError> {
+        let mut control = Control(Packet::allocate(Magic::CONTROL, Self::LENGTH)?);
+        control.data_mut().rid = rid;
+        Ok(control)
+    }
+
+    pub fn parse(packet: Packet) -> Option<Self> {
+        let len = packet.len();
+
+        if packet.header().flags != 0 {
+            return None;
+        }
+
+        if len == Self::LEGACY_LENGTH {
+            // zero extend packets from older nodes lacking the timestamp
+            let mut extended = Control::new(ReceiverId::zeroed()).ok()?;
+            extended.0.as_bytes_mut()[0..len].copy_from_slice(packet.as_bytes());
+            return Some(extended);
+        }
+
+        if len != Self::LENGTH {
+            return None;
+        }
+
+        Some(Control(packet))
+    }
+
+    pub fn as_packet(&self) -> &Packet {
+        &self.0
+    }
+
+    pub fn data(&self) -> &types::ControlPacket {
+        bytemuck::from_bytes(self.0.as_bytes())
+    }
+
+    pub fn data_mut(&mut self) -> &mut types::ControlPacket {
+        bytemuck::from_bytes_mut(self.0.as_bytes_mut())
+    }
+}
+
 #[derive(Debug)]
 pub struct Time(Packet);
 
 impl Time {
-    // packet delay has a linear relationship to packet size - it's important
-    // that time packets experience as similar delay as possible to audio
-    // packets for most accurate synchronisation, so we pad this packet out
-    // to the same size as the audio packet
-    const LENGTH: usize = Audio::LENGTH;
-
     // time packets are padded so that they are
     // the same length as audio packets:
     const DATA_RANGE: Range<usize> =
         0..size_of::<types::TimePacket>();
 
-    pub fn allocate() -> Result<Self, AllocError> {
-        Ok(Time(Packet::allocate(Magic::TIME, Self::LENGTH)?))
+    /// Allocates a time packet for a stream of the given format.
+    ///
+    /// Packet delay has a linear relationship to packet size - it's
+    /// important that time packets experience as similar delay as possible
+    /// to audio packets for most accurate synchronisation, so we pad this
+    /// packet out to the same size as the stream's uncompressed audio
+    /// packets
+    pub fn allocate(format: StreamFormat) -> Result<Self, AllocError> {
+        let length = Audio::length(format, SampleFormat::F32);
+        Ok(Time(Packet::allocate(Magic::TIME, length)?))
     }
 
     pub fn parse(packet: Packet) -> Option<Self> {
-        // we add some padding to the time packet so that it is the same
-        // length as audio packets
-        if packet.len() < Self::LENGTH {
+        // padding varies with the stream format, and by version, so accept
+        // any packet long enough to hold the time data
+        if packet.len() < Self::DATA_RANGE.end {
             return None;
         }
 
@@ -270,6 +858,11 @@ pub struct StatsReply(Packet);
 impl StatsReply {
     const LENGTH: usize = size_of::<types::StatsReplyPacket>();
 
+    // stats replies from nodes predating protocol versioning lack the
+    // trailing protocol info field, and anything after it
+    const LEGACY_LENGTH: usize =
+        Self::LENGTH - size_of::<ProtocolInfo>() - size_of::<ReceiverDiagnostics>();
+
     fn new(flags: StatsReplyFlags, data: types::StatsReplyPacket) -> Result<Self, AllocError> {
         let mut packet = Packet::allocate(Magic::STATS_REPLY, Self::LENGTH)?;
         packet.header_mut().flags = bytemuck::cast(flags);
@@ -282,22 +875,34 @@ impl StatsReply {
 
     pub fn source(sid: SessionId, node: NodeStats) -> Result<Self, AllocError> {
         let receiver = ReceiverStats::zeroed();
+        let diagnostics = ReceiverDiagnostics::zeroed();
 
         Self::new(
             StatsReplyFlags::IS_STREAM,
-            types::StatsReplyPacket { sid, receiver, node },
+            types::StatsReplyPacket { sid, receiver, node, protocol: ProtocolInfo::current(), diagnostics },
         )
     }
 
-    pub fn receiver(sid: SessionId, receiver: ReceiverStats, node: NodeStats) -> Result<Self, AllocError> {
+    pub fn receiver(sid: SessionId, receiver: ReceiverStats, diagnostics: ReceiverDiagnostics, node: NodeStats) -> Result<Self, AllocError> {
         Self::new(
             StatsReplyFlags::IS_RECEIVER,
-            types::StatsReplyPacket { sid, receiver, node },
+            types::StatsReplyPacket { sid, receiver, node, protocol: ProtocolInfo::current(), diagnostics },
         )
     }
 
     pub fn parse(packet: Packet) -> Option<Self> {
-        if packet.len() != Self::LENGTH {
+        let len = packet.len();
+
+        if (Self::LEGACY_LENGTH..Self::LENGTH).contains(&len) {
+            // zero extend replies from older nodes lacking trailing fields.
+            // legacy replies read as protocol version 0
+            let mut extended = Packet::allocate(Magic::STATS_REPLY, Self::LENGTH).ok()?;
+            extended.header_mut().flags = packet.header().flags;
+            extended.as_bytes_mut()[0..len].copy_from_slice(packet.as_bytes());
+            return Some(StatsReply(extended));
+        }
+
+        if len != Self::LENGTH {
             return None;
         }
 
@@ -320,3 +925,126 @@ impl StatsReply {
         bytemuck::from_bytes_mut(self.0.as_bytes_mut())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(seq: u64) -> AudioPacketHeader {
+        AudioPacketHeader {
+            sid: SessionId(1),
+            seq,
+            format: StreamFormat::DEFAULT,
+            ..AudioPacketHeader::zeroed()
+        }
+    }
+
+    // packet bytes assembled by hand, without an allocator
+    struct Bytes {
+        buffer: [u8; MAX_PACKET_SIZE],
+        len: usize,
+    }
+
+    impl Bytes {
+        fn new() -> Self {
+            Bytes { buffer: [0; MAX_PACKET_SIZE], len: 0 }
+        }
+
+        fn push(&mut self, bytes: &[u8]) {
+            self.buffer[self.len..][..bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+
+        fn as_slice(&self) -> &[u8] {
+            &self.buffer[0..self.len]
+        }
+    }
+
+    fn reparse(bytes: &[u8]) -> Option<PacketKind> {
+        let mut buffer = PacketBuffer::allocate(bytes.len()).unwrap();
+        buffer.as_bytes_mut().copy_from_slice(bytes);
+        Packet::from_buffer(buffer)?.parse(None)
+    }
+
+    #[test]
+    fn audio_round_trip() {
+        let audio = Audio::with_format(SampleFormat::S16LE, header(5)).unwrap();
+
+        let Some(PacketKind::Audio(parsed)) = reparse(audio.as_packet().frame()) else {
+            panic!("failed to parse audio packet");
+        };
+
+        assert_eq!(parsed.header().seq, 5);
+        assert_eq!(parsed.format(), SampleFormat::S16LE);
+        assert!(!parsed.as_packet().is_rewritten());
+    }
+
+    #[test]
+    fn reads_legacy_audio_header() {
+        let audio = Audio::with_format(SampleFormat::F32, header(5)).unwrap();
+        let bytes = audio.as_packet().frame();
+
+        // legacy packets have no flags and only the leading header fields
+        let packet_header = size_of::<types::PacketHeader>();
+        let mut legacy = Bytes::new();
+        legacy.push(&bytes[0..4]);
+        legacy.push(&0u32.to_ne_bytes());
+        legacy.push(&bytes[packet_header..][0..AudioPacketHeader::LEGACY_LENGTH]);
+        legacy.push(audio.data());
+
+        let Some(PacketKind::Audio(parsed)) = reparse(legacy.as_slice()) else {
+            panic!("failed to parse legacy audio packet");
+        };
+
+        assert_eq!(parsed.header().seq, 5);
+        assert_eq!(parsed.header().format, StreamFormat::DEFAULT);
+        assert_eq!(parsed.data(), audio.data());
+        assert!(parsed.as_packet().is_rewritten());
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let key = CipherKey::derive(b"key");
+        let audio = Audio::with_format(SampleFormat::S16LE, header(5)).unwrap();
+        let encrypted = AudioEncrypted::encrypt(audio.as_packet(), &key).unwrap();
+
+        let Some(PacketKind::AudioEncrypted(parsed)) = reparse(encrypted.as_packet().frame()) else {
+            panic!("failed to parse encrypted packet");
+        };
+
+        assert_eq!(parsed.header().seq, 5);
+
+        let Some(PacketKind::Audio(decrypted)) = parsed.decrypt(&key) else {
+            panic!("failed to decrypt packet");
+        };
+
+        assert_eq!(decrypted.format(), SampleFormat::S16LE);
+        assert_eq!(decrypted.as_packet().frame(), audio.as_packet().frame());
+        assert!(parsed.decrypt(&CipherKey::derive(b"other")).is_none());
+    }
+
+    #[test]
+    fn reads_longer_audio_header() {
+        let audio = Audio::with_format(SampleFormat::F32, header(5)).unwrap();
+        let bytes = audio.as_packet().frame();
+
+        // a future node with an extra 8 byte field on the end of the header
+        let packet_header = size_of::<types::PacketHeader>();
+        let length = AudioPacketHeader::LENGTH + 8;
+        let flags = SampleFormat::F32.into_flags() | ((length / 8) as u32) << 8;
+
+        let mut longer = Bytes::new();
+        longer.push(&bytes[0..4]);
+        longer.push(&flags.to_ne_bytes());
+        longer.push(&bytes[packet_header..][0..AudioPacketHeader::LENGTH]);
+        longer.push(&[0xff; 8]);
+        longer.push(audio.data());
+
+        let Some(PacketKind::Audio(parsed)) = reparse(longer.as_slice()) else {
+            panic!("failed to parse longer audio packet");
+        };
+
+        assert_eq!(parsed.header().seq, 5);
+        assert_eq!(parsed.data(), audio.data());
+    }
+}
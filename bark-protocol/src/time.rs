@@ -1,23 +1,24 @@
 use crate::packet;
 use crate::types::TimestampMicros;
-use crate::{SAMPLE_RATE, FRAMES_PER_PACKET, CHANNELS};
+use crate::{ChannelCount, SampleRate, FRAMES_PER_PACKET};
 
-/// A timestamp with implicit denominator SAMPLE_RATE
+/// A timestamp with implicit denominator of the stream's sample rate.
+/// Timestamps and durations at different sample rates must not be mixed.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Timestamp(u64);
 
 impl Timestamp {
-    pub fn to_micros_lossy(&self) -> TimestampMicros {
+    pub fn to_micros_lossy(&self, rate: SampleRate) -> TimestampMicros {
         let ts = u128::from(self.0);
-        let micros = (ts * 1_000_000) / u128::from(SAMPLE_RATE.0);
+        let micros = (ts * 1_000_000) / u128::from(rate);
         let micros = u64::try_from(micros)
             .expect("can't narrow timestamp to u64");
         TimestampMicros(micros)
     }
 
-    pub fn from_micros_lossy(micros: TimestampMicros) -> Timestamp {
+    pub fn from_micros_lossy(micros: TimestampMicros, rate: SampleRate) -> Timestamp {
         let micros = u128::from(micros.0);
-        let ts = (micros * u128::from(SAMPLE_RATE.0)) / 1_000_000;
+        let ts = (micros * u128::from(rate)) / 1_000_000;
         let ts = u64::try_from(ts)
             .expect("can't narrow timestamp to u64");
         Timestamp(ts)
@@ -42,7 +43,7 @@ impl Timestamp {
     }
 }
 
-/// A duration with implicit denominator SAMPLE_RATE
+/// A duration with implicit denominator of the stream's sample rate
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SampleDuration(u64);
 
@@ -57,25 +58,32 @@ impl SampleDuration {
         SampleDuration(samples)
     }
 
-    pub fn from_std_duration_lossy(duration: core::time::Duration) -> SampleDuration {
-        let duration = (duration.as_micros() * u128::from(SAMPLE_RATE)) / 1_000_000;
+    pub fn from_std_duration_lossy(duration: core::time::Duration, rate: SampleRate) -> SampleDuration {
+        let duration = (duration.as_micros() * u128::from(rate)) / 1_000_000;
         let duration = u64::try_from(duration).expect("can't narrow duration to u64");
         SampleDuration(duration)
     }
 
-    pub fn to_std_duration_lossy(&self) -> core::time::Duration {
-        let usecs = (u128::from(self.0) * 1_000_000) / u128::from(SAMPLE_RATE);
+    pub fn to_std_duration_lossy(&self, rate: SampleRate) -> core::time::Duration {
+        let usecs = (u128::from(self.0) * 1_000_000) / u128::from(rate);
         let usecs = u64::try_from(usecs).expect("can't narrow usecs to u64");
         core::time::Duration::from_micros(usecs)
     }
 
-    pub fn as_buffer_offset(&self) -> usize {
-        let offset = self.0 * u64::from(CHANNELS);
+    /// Converts a duration between sample rates, rounding down
+    pub fn convert_rate_lossy(&self, from: SampleRate, to: SampleRate) -> SampleDuration {
+        let frames = (u128::from(self.0) * u128::from(to)) / u128::from(from);
+        let frames = u64::try_from(frames).expect("can't narrow duration to u64");
+        SampleDuration(frames)
+    }
+
+    pub fn as_buffer_offset(&self, channels: ChannelCount) -> usize {
+        let offset = self.0 * u64::from(channels);
         usize::try_from(offset).unwrap()
     }
 
-    pub fn from_buffer_offset(offset: usize) -> Self {
-        let channels = usize::from(CHANNELS);
+    pub fn from_buffer_offset(offset: usize, channels: ChannelCount) -> Self {
+        let channels = usize::from(channels);
         assert!(offset % channels == 0);
 
         SampleDuration(u64::try_from(offset / channels).unwrap())
@@ -95,6 +103,10 @@ impl SampleDuration {
 pub struct ClockDelta(i64);
 
 impl ClockDelta {
+    pub fn from_micros(micros: i64) -> ClockDelta {
+        ClockDelta(micros)
+    }
+
     pub fn as_micros(&self) -> i64 {
         self.0
     }
@@ -113,18 +125,27 @@ impl ClockDelta {
         let t2_usec = time.receive_2.0 as i64;
         let t3_usec = time.stream_3.0 as i64;
 
-        // algorithm from the Precision Time Protocol page on Wikipedia
-        ClockDelta((t2_usec - t1_usec + t2_usec - t3_usec) / 2)
+        // receivers which don't record when they replied are assumed to
+        // have replied immediately
+        let reply_usec = match time.receive_reply.0 {
+            0 => t2_usec,
+            reply => reply as i64,
+        };
+
+        // algorithm from the Precision Time Protocol page on Wikipedia,
+        // with the receiver's reply time standing in for its receive time
+        // on the return leg
+        ClockDelta((t2_usec - t1_usec + reply_usec - t3_usec) / 2)
     }
 }
 
-/// A duration with denominator SAMPLE_RATE, but it's signed :)
+/// A duration with denominator of the stream's sample rate, but it's signed :)
 #[derive(Debug, Copy, Clone)]
 pub struct TimestampDelta(i64);
 
 impl TimestampDelta {
-    pub fn from_clock_delta_lossy(delta: ClockDelta) -> TimestampDelta {
-        TimestampDelta((delta.0 * i64::from(SAMPLE_RATE.0)) / 1_000_000)
+    pub fn from_clock_delta_lossy(delta: ClockDelta, rate: SampleRate) -> TimestampDelta {
+        TimestampDelta((delta.0 * i64::from(rate)) / 1_000_000)
     }
 
     pub fn abs(&self) -> SampleDuration {
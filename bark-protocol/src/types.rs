@@ -2,7 +2,7 @@ use bytemuck::{Pod, Zeroable};
 
 pub mod stats;
 
-use crate::SAMPLES_PER_PACKET;
+use crate::{StreamFormat, MAX_SAMPLES_PER_PACKET, PROTOCOL_VERSION};
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
 #[repr(transparent)]
@@ -13,6 +13,13 @@ impl Magic {
     pub const TIME: Magic        = Magic(0x01a79ae2);
     pub const STATS_REQ: Magic   = Magic(0x02a79ae2);
     pub const STATS_REPLY: Magic = Magic(0x03a79ae2);
+    pub const AUDIO_OPUS: Magic  = Magic(0x04a79ae2);
+    pub const AUDIO_FEC: Magic   = Magic(0x05a79ae2);
+    pub const NACK: Magic        = Magic(0x06a79ae2);
+    pub const AUDIO_ENCRYPTED: Magic = Magic(0x07a79ae2);
+    pub const GOODBYE: Magic     = Magic(0x08a79ae2);
+    pub const METADATA: Magic    = Magic(0x09a79ae2);
+    pub const CONTROL: Magic     = Magic(0x0aa79ae2);
 }
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
@@ -31,6 +38,11 @@ pub struct PacketHeader {
 ///     IEEE 754 very precisely specifies the bit layout of floats.
 ///
 ///     - https://doc.rust-lang.org/std/primitive.f32.html
+///
+/// The header is extensible. New fields are only ever added at the end,
+/// and the length of the header is carried in the packet flags (see
+/// `AudioPacketHeader::flags`), so that nodes can read headers longer or
+/// shorter than their own.
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
 pub struct AudioPacketHeader {
@@ -47,9 +59,219 @@ pub struct AudioPacketHeader {
 
     // data timestamp - the stream's clock when packet is sent
     pub dts: TimestampMicros,
+
+    // fields above are all that nodes predating protocol versioning send,
+    // fields below are zeroed when reading a header from such a node
+
+    // named stream this packet belongs to, allowing several streams to
+    // share a multicast group
+    pub stream: StreamId,
+
+    // sample rate and channel count of the stream. zero from nodes which
+    // predate this field, which only send the default format
+    pub format: StreamFormat,
+
+    // takeover priority of the source, used by senders configured with
+    // the priority takeover policy
+    pub priority: u32,
+    pub _pad: u32,
+}
+
+impl AudioPacketHeader {
+    /// Length of the header we send
+    pub const LENGTH: usize = core::mem::size_of::<AudioPacketHeader>();
+
+    /// Length of the header sent by nodes predating protocol versioning
+    pub const LEGACY_LENGTH: usize = 32;
+
+    // the second byte of audio packet flags holds the header length in
+    // 8 byte words. zero means a legacy header
+    const LENGTH_SHIFT: u32 = 8;
+    const LENGTH_MASK: u32 = 0xff;
+    const LENGTH_UNIT: usize = 8;
+
+    /// Packet flags advertising the length of our header, to be combined
+    /// with any packet specific flags
+    pub const fn flags() -> u32 {
+        ((Self::LENGTH / Self::LENGTH_UNIT) as u32) << Self::LENGTH_SHIFT
+    }
+
+    /// Length of the audio header in a packet with the given flags
+    pub fn length_from_flags(flags: u32) -> usize {
+        match (flags >> Self::LENGTH_SHIFT) & Self::LENGTH_MASK {
+            0 => Self::LEGACY_LENGTH,
+            words => words as usize * Self::LENGTH_UNIT,
+        }
+    }
+
+    /// Flags of an audio packet with the header length removed, leaving
+    /// only the packet specific flags
+    pub fn packet_flags(flags: u32) -> u32 {
+        flags & !(Self::LENGTH_MASK << Self::LENGTH_SHIFT)
+    }
+}
+
+/// Buffer large enough for any f32 audio packet
+pub type AudioPacketBuffer = [f32; MAX_SAMPLES_PER_PACKET];
+
+/// Sample format of the data in an audio packet, carried in the flags field
+/// of the packet header. F32 is zero so that packets from senders which
+/// predate this field are still understood.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    F32,
+    S16LE,
+    S24LE,
+}
+
+impl SampleFormat {
+    pub fn from_flags(flags: u32) -> Option<Self> {
+        match flags {
+            0 => Some(SampleFormat::F32),
+            1 => Some(SampleFormat::S16LE),
+            2 => Some(SampleFormat::S24LE),
+            _ => None,
+        }
+    }
+
+    pub fn into_flags(self) -> u32 {
+        match self {
+            SampleFormat::F32 => 0,
+            SampleFormat::S16LE => 1,
+            SampleFormat::S24LE => 2,
+        }
+    }
+
+    pub const fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::F32 => 4,
+            SampleFormat::S16LE => 2,
+            SampleFormat::S24LE => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct FecPacketHeader {
+    pub sid: SessionId,
+
+    // seq of the first audio packet covered by this fec packet
+    pub seq: u64,
+
+    // number of consecutive audio packets covered
+    pub count: u32,
+
+    // xor of the lengths of all covered packets, used to recover the length
+    // of a lost packet
+    pub length: u32,
+}
+
+/// Sent by a receiver to the stream source to request retransmission of
+/// lost audio packets
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct NackPacket {
+    pub sid: SessionId,
+
+    // seq of the first missing packet
+    pub seq: u64,
+
+    // number of consecutive missing packets, starting at seq
+    pub count: u32,
+
+    pub _pad: u32,
+}
+
+/// Broadcast by a stream source when it stops streaming
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct GoodbyePacket {
+    pub sid: SessionId,
+}
+
+/// Describes a stream, broadcast periodically by stream sources. Strings
+/// are UTF-8, padded with zeroes. Empty strings mean not set.
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct MetadataPacket {
+    pub sid: SessionId,
+    pub name: [u8; 64],
+    pub title: [u8; 128],
+    pub artist: [u8; 128],
+}
+
+impl MetadataPacket {
+    pub fn name(&self) -> &str {
+        metadata_str(&self.name)
+    }
+
+    pub fn title(&self) -> &str {
+        metadata_str(&self.title)
+    }
+
+    pub fn artist(&self) -> &str {
+        metadata_str(&self.artist)
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        set_metadata_str(&mut self.name, name);
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        set_metadata_str(&mut self.title, title);
+    }
+
+    pub fn set_artist(&mut self, artist: &str) {
+        set_metadata_str(&mut self.artist, artist);
+    }
+}
+
+fn metadata_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+
+    // invalid strings are treated as not set
+    core::str::from_utf8(&field[0..len]).unwrap_or_default()
+}
+
+fn set_metadata_str(field: &mut [u8], value: &str) {
+    // truncate to fit, without splitting a character
+    let mut len = core::cmp::min(value.len(), field.len());
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    field.fill(0);
+    field[0..len].copy_from_slice(&value.as_bytes()[0..len]);
+}
+
+/// Sent to receivers to remotely change their output volume
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ControlPacket {
+    // receiver to control, or broadcast to control every receiver
+    pub rid: ReceiverId,
+
+    // which of the settings below to apply
+    pub flags: ControlFlags,
+
+    // output volume as a fraction of full scale, from 0.0 to 1.0
+    pub volume: f32,
+
+    // wall clock time the packet was sent, so that receivers can reject
+    // replayed packets. zero from nodes which predate this field
+    pub sent: TimestampMicros,
 }
 
-pub type AudioPacketBuffer = [f32; SAMPLES_PER_PACKET];
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ControlFlags: u32 {
+        const SET_VOLUME = 0x01;
+        const MUTE       = 0x02;
+        const UNMUTE     = 0x04;
+    }
+}
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
@@ -60,6 +282,19 @@ pub struct TimePacket {
     pub stream_1: TimestampMicros,
     pub receive_2: TimestampMicros,
     pub stream_3: TimestampMicros,
+
+    // these fields live in what was previously padding, so nodes predating
+    // protocol versioning leave them zeroed
+    pub stream_protocol: ProtocolInfo,
+    pub receive_protocol: ProtocolInfo,
+
+    // named stream the time packet belongs to
+    pub stream: StreamId,
+
+    // receiver's time when sending its reply, so that time spent between
+    // receiving and replying can be excluded from the round trip. zero
+    // from receivers which only record `receive_2`
+    pub receive_reply: TimestampMicros,
 }
 
 #[derive(Debug, PartialEq)]
@@ -68,7 +303,7 @@ pub enum TimePhase {
     /// withn only `stream_1` set
     Broadcast,
 
-    /// A receiver replies, setting `receive_2`
+    /// A receiver replies, setting `receive_2` and `receive_reply`
     ReceiverReply,
 
     /// Finally, the stream replies (over unicast) again, setting `stream_3`
@@ -96,6 +331,23 @@ impl TimePacket {
         // incoherent + invalid time packet
         None
     }
+
+    /// Time the exchange spent crossing the network in microseconds, not
+    /// counting any time the receiver took to reply when it has told us
+    pub fn round_trip_usec(&self) -> Option<u64> {
+        let rtt = self.stream_3.0.checked_sub(self.stream_1.0)?;
+        rtt.checked_sub(self.receive_processing_usec()?)
+    }
+
+    /// Time the receiver took between receiving the broadcast and sending
+    /// its reply, zero if the receiver doesn't record it
+    fn receive_processing_usec(&self) -> Option<u64> {
+        if self.receive_reply.0 == 0 {
+            return Some(0);
+        }
+
+        self.receive_reply.0.checked_sub(self.receive_2.0)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
@@ -104,6 +356,51 @@ pub struct StatsReplyPacket {
     pub sid: SessionId,
     pub receiver: stats::receiver::ReceiverStats,
     pub node: stats::node::NodeStats,
+    pub protocol: ProtocolInfo,
+    // new fields are only ever added at the end of the reply, replies from
+    // older nodes are zero extended
+    pub diagnostics: stats::receiver::ReceiverDiagnostics,
+}
+
+/// Protocol version and optional features supported by a node
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl ProtocolInfo {
+    pub fn current() -> Self {
+        ProtocolInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::all(),
+        }
+    }
+
+    /// Nodes predating protocol versioning report all zeroes
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
+    #[repr(transparent)]
+    pub struct Capabilities: u32 {
+        /// Can send and receive Opus compressed audio packets
+        const AUDIO_OPUS    = 0x01;
+        /// Can send and receive s16le and s24le audio packets
+        const AUDIO_INT_PCM = 0x02;
+        /// Can recover lost audio packets from fec packets
+        const AUDIO_FEC     = 0x04;
+        /// Can request and answer retransmission of lost audio packets
+        const AUDIO_NACK    = 0x08;
+        /// Can decrypt encrypted audio packets
+        const AUDIO_ENCRYPTED = 0x10;
+        /// Accepts remote volume and mute control
+        const CONTROL       = 0x20;
+    }
 }
 
 bitflags::bitflags! {
@@ -128,6 +425,13 @@ impl ReceiverId {
         ReceiverId(0)
     }
 
+    /// Derives a receiver id from a receiver name, so that receivers can be
+    /// addressed by name
+    pub fn from_name(name: &str) -> Self {
+        // zero is reserved for broadcast
+        ReceiverId(core::cmp::max(fnv1a(name), 1))
+    }
+
     pub fn is_broadcast(&self) -> bool {
         self.0 == 0
     }
@@ -140,3 +444,28 @@ impl ReceiverId {
 #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, PartialOrd)]
 #[repr(transparent)]
 pub struct SessionId(pub i64);
+
+/// Identifies a named stream. Streams without a name use `StreamId::UNNAMED`
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct StreamId(pub u64);
+
+impl StreamId {
+    pub const UNNAMED: StreamId = StreamId(0);
+
+    /// Derives a stream id from a stream name
+    pub fn from_name(name: &str) -> Self {
+        // zero is reserved for unnamed streams
+        StreamId(core::cmp::max(fnv1a(name), 1))
+    }
+}
+
+/// 64 bit FNV-1a hash, used to derive ids from names
+fn fnv1a(name: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    name.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
@@ -1,6 +1,7 @@
 use bitflags::bitflags;
 use bytemuck::{Zeroable, Pod};
 
+use crate::SampleRate;
 use crate::time::{SampleDuration, Timestamp};
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
@@ -8,7 +9,9 @@ use crate::time::{SampleDuration, Timestamp};
 pub struct ReceiverStats {
     flags: ReceiverStatsFlags,
     stream_status: u8,
-    _pad: [u8; 6],
+    // static playback offset configured on the receiver, zero unless set
+    offset_ms: i16,
+    volume: f32,
 
     audio_latency: f64,
     buffer_length: f64,
@@ -21,6 +24,7 @@ pub enum StreamStatus {
     Sync,
     Slew,
     Miss,
+    Idle,
 }
 
 impl StreamStatus {
@@ -30,6 +34,7 @@ impl StreamStatus {
             StreamStatus::Sync => 2,
             StreamStatus::Slew => 3,
             StreamStatus::Miss => 4,
+            StreamStatus::Idle => 5,
         }
     }
 
@@ -39,6 +44,7 @@ impl StreamStatus {
             2 => Some(StreamStatus::Sync),
             3 => Some(StreamStatus::Slew),
             4 => Some(StreamStatus::Miss),
+            5 => Some(StreamStatus::Idle),
             _ => None,
         }
     }
@@ -52,6 +58,8 @@ bitflags! {
         const HAS_BUFFER_LENGTH   = 0x08;
         const HAS_NETWORK_LATENCY = 0x10;
         const HAS_PREDICT_OFFSET  = 0x20;
+        const HAS_VOLUME          = 0x40;
+        const MUTED               = 0x80;
     }
 }
 
@@ -101,16 +109,31 @@ impl ReceiverStats {
         self.field(ReceiverStatsFlags::HAS_PREDICT_OFFSET, self.predict_offset)
     }
 
-    pub fn set_audio_latency(&mut self, request_pts: Timestamp, packet_pts: Timestamp) {
-        let request_micros = request_pts.to_micros_lossy().0 as f64;
-        let packet_micros = packet_pts.to_micros_lossy().0 as f64;
+    /// Output volume as a fraction of full scale
+    pub fn volume(&self) -> Option<f64> {
+        self.field(ReceiverStatsFlags::HAS_VOLUME, f64::from(self.volume))
+    }
+
+    pub fn muted(&self) -> bool {
+        self.flags.contains(ReceiverStatsFlags::MUTED)
+    }
+
+    /// Static playback offset in milliseconds, positive offsets delay
+    /// playback
+    pub fn offset_ms(&self) -> i16 {
+        self.offset_ms
+    }
+
+    pub fn set_audio_latency(&mut self, request_pts: Timestamp, packet_pts: Timestamp, rate: SampleRate) {
+        let request_micros = request_pts.to_micros_lossy(rate).0 as f64;
+        let packet_micros = packet_pts.to_micros_lossy(rate).0 as f64;
 
         self.audio_latency = (request_micros - packet_micros) / 1_000_000.0;
         self.flags.insert(ReceiverStatsFlags::HAS_AUDIO_LATENCY);
     }
 
-    pub fn set_buffer_length(&mut self, length: SampleDuration) {
-        self.buffer_length = length.to_std_duration_lossy().as_micros() as f64 / 1_000_000.0;
+    pub fn set_buffer_length(&mut self, length: SampleDuration, rate: SampleRate) {
+        self.buffer_length = length.to_std_duration_lossy(rate).as_micros() as f64 / 1_000_000.0;
         self.flags.insert(ReceiverStatsFlags::HAS_BUFFER_LENGTH);
     }
 
@@ -123,4 +146,145 @@ impl ReceiverStats {
         self.predict_offset = diff_usec as f64 / 1_000_000.0;
         self.flags.insert(ReceiverStatsFlags::HAS_PREDICT_OFFSET);
     }
+
+    pub fn set_offset_ms(&mut self, offset_ms: i64) {
+        self.offset_ms = offset_ms.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+    }
+
+    pub fn set_volume(&mut self, volume: f32, muted: bool) {
+        self.volume = volume;
+        self.flags.insert(ReceiverStatsFlags::HAS_VOLUME);
+        self.flags.set(ReceiverStatsFlags::MUTED, muted);
+    }
+}
+
+/// Receiver statistics added after `ReceiverStats`, sent at the end of stats
+/// replies so that replies from older nodes can be zero extended
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+pub struct ReceiverDiagnostics {
+    flags: ReceiverDiagnosticsFlags,
+    _pad: u32,
+
+    concealed_packets: u64,
+    late_packet_rate: f64,
+    requested_latency: f64,
+    clock_drift: f64,
+    clock_samples_accepted: u64,
+    clock_samples_rejected: u64,
+    rtt_spread: f64,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Zeroable, Pod)]
+    #[repr(transparent)]
+    pub struct ReceiverDiagnosticsFlags: u32 {
+        const HAS_CONCEALED_PACKETS = 0x01;
+        const HAS_LATE_PACKET_RATE  = 0x02;
+        const HAS_REQUESTED_LATENCY = 0x04;
+        const HAS_CLOCK_DRIFT       = 0x08;
+        const HAS_CLOCK_SAMPLES     = 0x10;
+        const HAS_RTT_SPREAD        = 0x20;
+        const HAS_TIMESTAMP_SOURCE  = 0x40;
+        // packets are timestamped by the kernel on arrival, rather than
+        // by us once we get around to reading them
+        const KERNEL_TIMESTAMPS     = 0x80;
+    }
+}
+
+impl Default for ReceiverDiagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReceiverDiagnostics {
+    pub fn new() -> Self {
+        ReceiverDiagnostics::zeroed()
+    }
+
+    fn field<T>(&self, flag: ReceiverDiagnosticsFlags, value: T) -> Option<T> {
+        if self.flags.contains(flag) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Number of lost packets concealed since the stream started
+    pub fn concealed_packets(&self) -> Option<u64> {
+        self.field(ReceiverDiagnosticsFlags::HAS_CONCEALED_PACKETS, self.concealed_packets)
+    }
+
+    /// Fraction of recent packets which arrived too late to be played
+    pub fn late_packet_rate(&self) -> Option<f64> {
+        self.field(ReceiverDiagnosticsFlags::HAS_LATE_PACKET_RATE, self.late_packet_rate)
+    }
+
+    /// Lowest stream latency in seconds the receiver can play at without
+    /// packets arriving late, only set by receivers measuring it
+    pub fn requested_latency(&self) -> Option<f64> {
+        self.field(ReceiverDiagnosticsFlags::HAS_REQUESTED_LATENCY, self.requested_latency)
+    }
+
+    /// Estimated rate the receiver's clock runs ahead of the stream
+    /// source's, in parts per million
+    pub fn clock_drift(&self) -> Option<f64> {
+        self.field(ReceiverDiagnosticsFlags::HAS_CLOCK_DRIFT, self.clock_drift)
+    }
+
+    /// Number of clock samples used and discarded for having a longer
+    /// round trip than others
+    pub fn clock_samples(&self) -> Option<(u64, u64)> {
+        self.field(ReceiverDiagnosticsFlags::HAS_CLOCK_SAMPLES,
+            (self.clock_samples_accepted, self.clock_samples_rejected))
+    }
+
+    /// Difference between the longest and shortest recent time packet round
+    /// trips in seconds
+    pub fn rtt_spread(&self) -> Option<f64> {
+        self.field(ReceiverDiagnosticsFlags::HAS_RTT_SPREAD, self.rtt_spread)
+    }
+
+    /// Whether received packets are timestamped by the kernel
+    pub fn kernel_timestamps(&self) -> Option<bool> {
+        self.field(ReceiverDiagnosticsFlags::HAS_TIMESTAMP_SOURCE,
+            self.flags.contains(ReceiverDiagnosticsFlags::KERNEL_TIMESTAMPS))
+    }
+
+    pub fn add_concealed_packet(&mut self) {
+        self.concealed_packets += 1;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_CONCEALED_PACKETS);
+    }
+
+    pub fn set_late_packet_rate(&mut self, rate: f64) {
+        self.late_packet_rate = rate;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_LATE_PACKET_RATE);
+    }
+
+    pub fn set_clock_drift(&mut self, ppm: f64) {
+        self.clock_drift = ppm;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_CLOCK_DRIFT);
+    }
+
+    pub fn set_clock_samples(&mut self, accepted: u64, rejected: u64) {
+        self.clock_samples_accepted = accepted;
+        self.clock_samples_rejected = rejected;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_CLOCK_SAMPLES);
+    }
+
+    pub fn set_rtt_spread(&mut self, spread: core::time::Duration) {
+        self.rtt_spread = spread.as_micros() as f64 / 1_000_000.0;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_RTT_SPREAD);
+    }
+
+    pub fn set_requested_latency(&mut self, latency: core::time::Duration) {
+        self.requested_latency = latency.as_micros() as f64 / 1_000_000.0;
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_REQUESTED_LATENCY);
+    }
+
+    pub fn set_kernel_timestamps(&mut self, kernel: bool) {
+        self.flags.set(ReceiverDiagnosticsFlags::KERNEL_TIMESTAMPS, kernel);
+        self.flags.insert(ReceiverDiagnosticsFlags::HAS_TIMESTAMP_SOURCE);
+    }
 }
@@ -0,0 +1,283 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use bark_protocol::{ChannelCount, StreamFormat};
+use bark_protocol::packet::Audio;
+use bark_protocol::types::SampleFormat;
+
+/// Speaker positions, in the order they appear in standard layouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    SideLeft,
+    SideRight,
+    BackLeft,
+    BackRight,
+}
+
+impl Position {
+    const NAMES: [(&'static str, Position); 8] = [
+        ("FL", Position::FrontLeft),
+        ("FR", Position::FrontRight),
+        ("FC", Position::FrontCenter),
+        ("LFE", Position::LowFrequency),
+        ("SL", Position::SideLeft),
+        ("SR", Position::SideRight),
+        ("BL", Position::BackLeft),
+        ("BR", Position::BackRight),
+    ];
+
+    /// Standard channel layout for streams with the given channel count
+    fn layout(channels: ChannelCount) -> &'static [Position] {
+        use Position::*;
+
+        match channels.0 {
+            1 => &[FrontCenter],
+            2 => &[FrontLeft, FrontRight],
+            6 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, SideLeft, SideRight],
+            8 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight, SideLeft, SideRight],
+            _ => &[],
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        Self::NAMES.iter()
+            .find(|(_, position)| position == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+}
+
+/// A stream channel selected by a receiver, either by position or by
+/// zero-based index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Position(Position),
+    Index(usize),
+}
+
+impl Channel {
+    fn resolve(&self, channels: ChannelCount) -> Option<usize> {
+        match self {
+            Channel::Position(position) => {
+                Position::layout(channels).iter().position(|p| p == position)
+            }
+            Channel::Index(index) if *index < usize::from(channels) => Some(*index),
+            Channel::Index(_) => None,
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Position(position) => write!(f, "{}", position.name()),
+            Channel::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+/// Which stream channels a receiver plays on each of its output channels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelMap {
+    /// Play all channels, downmixing or upmixing to the output as needed
+    Auto,
+    /// Play only the selected channels. A single channel is played on
+    /// every output channel, otherwise each output channel plays the
+    /// channel at its position in the list.
+    Select(Vec<Channel>),
+}
+
+#[derive(Debug)]
+pub struct InvalidChannelMap(String);
+
+impl Display for InvalidChannelMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid channel {:?}, expected auto, a channel index, or one of: ", self.0)?;
+
+        let names = Position::NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+impl FromStr for ChannelMap {
+    type Err = InvalidChannelMap;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(ChannelMap::Auto);
+        }
+
+        let channels = s.split(',')
+            .map(|name| name.trim())
+            .map(|name| {
+                if let Ok(index) = name.parse() {
+                    return Ok(Channel::Index(index));
+                }
+
+                Position::NAMES.iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, position)| Channel::Position(*position))
+                    .ok_or_else(|| InvalidChannelMap(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ChannelMap::Select(channels))
+    }
+}
+
+impl ChannelMap {
+    /// Builds the mix from a stream's channels to our output channels
+    pub fn mix(&self, input: ChannelCount, output: ChannelCount) -> ChannelMix {
+        let mut mix = ChannelMix::silent(input, output);
+
+        let in_channels = usize::from(input);
+        let out_channels = usize::from(output);
+
+        match self {
+            // mono streams are always played on every output channel
+            _ if in_channels == 1 => {
+                for out in 0..out_channels {
+                    mix.set(out, 0, 1.0);
+                }
+            }
+            ChannelMap::Auto => {
+                mix.auto();
+            }
+            ChannelMap::Select(channels) => {
+                for out in 0..out_channels {
+                    let channel = if channels.len() == 1 {
+                        channels[0]
+                    } else if let Some(channel) = channels.get(out) {
+                        *channel
+                    } else {
+                        continue;
+                    };
+
+                    match channel.resolve(input) {
+                        Some(index) => mix.set(out, index, 1.0),
+                        None => eprintln!("\nwarning: channel {channel} not present in {in_channels} channel stream"),
+                    }
+                }
+            }
+        }
+
+        mix
+    }
+}
+
+/// Gain matrix from stream channels to output channels
+pub struct ChannelMix {
+    input: ChannelCount,
+    output: ChannelCount,
+    // row per output channel, column per input channel
+    gains: Vec<f32>,
+}
+
+impl ChannelMix {
+    fn silent(input: ChannelCount, output: ChannelCount) -> Self {
+        let len = usize::from(input) * usize::from(output);
+        ChannelMix { input, output, gains: vec![0.0; len] }
+    }
+
+    fn set(&mut self, output: usize, input: usize, gain: f32) {
+        self.gains[output * usize::from(self.input) + input] = gain;
+    }
+
+    fn auto(&mut self) {
+        use Position::*;
+
+        let in_channels = usize::from(self.input);
+        let out_channels = usize::from(self.output);
+        let layout = Position::layout(self.input);
+
+        if in_channels == out_channels {
+            for channel in 0..in_channels {
+                self.set(channel, channel, 1.0);
+            }
+        } else if out_channels == 2 && layout.len() > 2 {
+            // standard downmix of surround layouts to stereo, centre and
+            // surround channels are mixed in at -3dB. LFE is dropped.
+            let gain = |position: Position, side: Position| {
+                match position {
+                    _ if position == side => 1.0,
+                    FrontCenter => FRAC_1_SQRT_2,
+                    SideLeft | BackLeft if side == FrontLeft => FRAC_1_SQRT_2,
+                    SideRight | BackRight if side == FrontRight => FRAC_1_SQRT_2,
+                    _ => 0.0,
+                }
+            };
+
+            for (out, side) in [FrontLeft, FrontRight].into_iter().enumerate() {
+                let total = layout.iter().map(|p| gain(*p, side)).sum::<f32>();
+
+                for (index, position) in layout.iter().enumerate() {
+                    self.set(out, index, gain(*position, side) / total);
+                }
+            }
+        } else if out_channels == 1 {
+            // average all channels but LFE
+            let channels = (0..in_channels)
+                .filter(|index| layout.get(*index) != Some(&LowFrequency))
+                .collect::<Vec<_>>();
+
+            for index in &channels {
+                self.set(0, *index, 1.0 / channels.len() as f32);
+            }
+        } else {
+            // no better idea, map channels by index
+            for channel in 0..std::cmp::min(in_channels, out_channels) {
+                self.set(channel, channel, 1.0);
+            }
+        }
+    }
+
+    fn is_identity(&self) -> bool {
+        if self.input != self.output {
+            return false;
+        }
+
+        let channels = usize::from(self.input);
+
+        self.gains.iter().enumerate().all(|(i, gain)| {
+            let expected = if i / channels == i % channels { 1.0 } else { 0.0 };
+            *gain == expected
+        })
+    }
+
+    /// Mixes an f32 audio packet to the output channel count
+    pub fn apply(&self, packet: Audio) -> Audio {
+        if self.is_identity() {
+            return packet;
+        }
+
+        let input = packet.stream_format();
+        assert!(input.channels == self.input);
+
+        let mut header = *packet.header();
+        header.format = StreamFormat::new(input.sample_rate, self.output);
+
+        let mut mixed = Audio::with_format(SampleFormat::F32, header)
+            .expect("allocate Audio packet");
+
+        let in_channels = usize::from(self.input);
+        let out_channels = usize::from(self.output);
+
+        let in_frames = packet.buffer().chunks_exact(in_channels);
+        let out_frames = mixed.buffer_mut().chunks_exact_mut(out_channels);
+
+        for (in_frame, out_frame) in in_frames.zip(out_frames) {
+            let rows = self.gains.chunks_exact(in_channels);
+
+            for (out, gains) in out_frame.iter_mut().zip(rows) {
+                *out = in_frame.iter().zip(gains).map(|(sample, gain)| sample * gain).sum();
+            }
+        }
+
+        mixed
+    }
+}
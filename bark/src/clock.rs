@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bark_protocol::time::ClockDelta;
+use bark_protocol::types::TimestampMicros;
+
+/// Picks out the clock delta samples worth using. Exchanges which took
+/// longer than others on the network were likely delayed in one direction
+/// more than the other, skewing the delta measured from them, so only
+/// samples with the shortest round trip of the last few are accepted
+pub struct ClockFilter {
+    // round trip times of recent exchanges
+    rtts: VecDeque<Duration>,
+    accepted: u64,
+    rejected: u64,
+}
+
+impl ClockFilter {
+    // number of recent exchanges the shortest round trip is picked from
+    const WINDOW: usize = 8;
+
+    pub fn new() -> Self {
+        ClockFilter {
+            rtts: VecDeque::with_capacity(Self::WINDOW),
+            accepted: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Records the round trip time of an exchange, returning whether the
+    /// clock delta measured from it should be used
+    pub fn observe(&mut self, rtt: Duration) -> bool {
+        if self.rtts.len() == Self::WINDOW {
+            self.rtts.pop_front();
+        }
+
+        let accept = self.rtts.iter().all(|other| rtt <= *other);
+        self.rtts.push_back(rtt);
+
+        if accept {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+
+        accept
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Difference between the longest and shortest recent round trips
+    pub fn spread(&self) -> Option<Duration> {
+        let min = self.rtts.iter().min()?;
+        let max = self.rtts.iter().max()?;
+        Some(*max - *min)
+    }
+}
+
+/// Estimates the difference between our clock and a stream source's clock,
+/// along with the rate at which the two drift apart, from time packet
+/// exchanges
+pub struct ClockEstimate {
+    samples: VecDeque<Sample>,
+    fit: Option<Fit>,
+    // whether the fit estimates drift, rather than being the median delta
+    // with drift assumed to be zero
+    drift_measured: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    // our time when the sample was taken
+    local_usec: i64,
+    delta_usec: i64,
+}
+
+/// Straight line fit of clock delta against our time
+#[derive(Clone, Copy)]
+struct Fit {
+    // our time the fit is relative to
+    origin_usec: i64,
+    // clock delta at origin
+    offset_usec: f64,
+    // change in clock delta per microsecond of our time
+    drift: f64,
+}
+
+impl ClockEstimate {
+    // number of recent samples fitted. only around one in eight exchanges
+    // pass the clock filter, so at one every 200ms this covers a few
+    // minutes
+    const WINDOW: usize = 128;
+
+    // samples needed before estimating drift, until then the median
+    // delta is used
+    const MIN_SAMPLES: usize = 16;
+    const MIN_SPAN: Duration = Duration::from_secs(3);
+
+    // samples further than this many median absolute deviations from the
+    // initial fit are discarded as outliers
+    const MAX_DEVIATIONS: f64 = 3.0;
+
+    // any real pair of clocks drift apart far slower than this, a larger
+    // estimate means the samples are bad
+    const MAX_DRIFT_PPM: f64 = 500.0;
+
+    pub fn new() -> Self {
+        ClockEstimate {
+            samples: VecDeque::with_capacity(Self::WINDOW),
+            fit: None,
+            drift_measured: false,
+        }
+    }
+
+    pub fn observe(&mut self, local: TimestampMicros, delta: ClockDelta) {
+        if self.samples.len() == Self::WINDOW {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(Sample {
+            local_usec: local.0 as i64,
+            delta_usec: delta.as_micros(),
+        });
+
+        let fit = self.fit_drift();
+        self.drift_measured = fit.is_some();
+        self.fit = fit.or_else(|| self.fit_median());
+    }
+
+    /// Estimated clock delta at the given time on our clock
+    pub fn delta_at(&self, local: TimestampMicros) -> Option<ClockDelta> {
+        let fit = self.fit?;
+        let elapsed = (local.0 as i64 - fit.origin_usec) as f64;
+        let delta = fit.offset_usec + fit.drift * elapsed;
+        Some(ClockDelta::from_micros(delta.round() as i64))
+    }
+
+    /// Estimated rate at which our clock runs ahead of the source's, in
+    /// parts per million. `None` until there are enough samples to estimate
+    /// it
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if !self.drift_measured {
+            return None;
+        }
+
+        self.fit.map(|fit| fit.drift * 1_000_000.0)
+    }
+
+    /// Fit with no drift, for when there aren't yet enough samples to
+    /// estimate it
+    fn fit_median(&self) -> Option<Fit> {
+        let mut deltas = self.samples.iter().map(|s| s.delta_usec).collect::<Vec<_>>();
+        deltas.sort();
+
+        let last = self.samples.back()?;
+
+        Some(Fit {
+            origin_usec: last.local_usec,
+            offset_usec: deltas[deltas.len() / 2] as f64,
+            drift: 0.0,
+        })
+    }
+
+    fn fit_drift(&self) -> Option<Fit> {
+        let first = self.samples.front()?;
+        let last = self.samples.back()?;
+
+        let span = u64::try_from(last.local_usec - first.local_usec).unwrap_or(0);
+
+        if self.samples.len() < Self::MIN_SAMPLES || Duration::from_micros(span) < Self::MIN_SPAN {
+            return None;
+        }
+
+        let origin_usec = last.local_usec;
+        let samples = self.samples.iter().copied().collect::<Vec<_>>();
+        let fit = linear_fit(&samples, origin_usec)?;
+
+        // refit without outliers, exchanges delayed on the network give
+        // deltas which are way off
+        let mut residuals = samples.iter()
+            .map(|sample| residual(&fit, sample).abs())
+            .collect::<Vec<_>>();
+        residuals.sort_by(f64::total_cmp);
+        let deviation = residuals[residuals.len() / 2];
+
+        let inliers = samples.iter()
+            .filter(|sample| residual(&fit, sample).abs() <= deviation * Self::MAX_DEVIATIONS)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let fit = linear_fit(&inliers, origin_usec).unwrap_or(fit);
+
+        if (fit.drift * 1_000_000.0).abs() > Self::MAX_DRIFT_PPM {
+            return None;
+        }
+
+        Some(fit)
+    }
+}
+
+/// Least squares fit of clock delta against time
+fn linear_fit(samples: &[Sample], origin_usec: i64) -> Option<Fit> {
+    if samples.len() < 2 {
+        return None;
+    }
+
+    let count = samples.len() as f64;
+    let x = |sample: &Sample| (sample.local_usec - origin_usec) as f64;
+    let y = |sample: &Sample| sample.delta_usec as f64;
+
+    let mean_x = samples.iter().map(x).sum::<f64>() / count;
+    let mean_y = samples.iter().map(y).sum::<f64>() / count;
+
+    let sxx = samples.iter().map(|s| (x(s) - mean_x).powi(2)).sum::<f64>();
+    let sxy = samples.iter().map(|s| (x(s) - mean_x) * (y(s) - mean_y)).sum::<f64>();
+
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let drift = sxy / sxx;
+
+    Some(Fit {
+        origin_usec,
+        offset_usec: mean_y - drift * mean_x,
+        drift,
+    })
+}
+
+fn residual(fit: &Fit, sample: &Sample) -> f64 {
+    let elapsed = (sample.local_usec - fit.origin_usec) as f64;
+    sample.delta_usec as f64 - (fit.offset_usec + fit.drift * elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feeds samples taken every 200ms from clocks drifting apart at the
+    // given rate, with some jitter and the occasional delayed exchange
+    fn estimate(drift_ppm: f64) -> ClockEstimate {
+        let mut estimate = ClockEstimate::new();
+
+        for index in 0..100i64 {
+            let local_usec = 1_000_000_000 + index * 200_000;
+            let jitter = (index % 7 - 3) * 5;
+            let outlier = if index % 25 == 0 { 2_000 } else { 0 };
+
+            let elapsed = (local_usec - 1_000_000_000) as f64;
+            let delta = 50_000 + (drift_ppm * elapsed / 1_000_000.0) as i64 + jitter + outlier;
+
+            estimate.observe(TimestampMicros(local_usec as u64), ClockDelta::from_micros(delta));
+        }
+
+        estimate
+    }
+
+    #[test]
+    fn recovers_drift() {
+        for drift_ppm in [-40.0, 0.0, 25.0] {
+            let estimate = estimate(drift_ppm);
+            let estimated = estimate.drift_ppm().unwrap();
+            assert!((estimated - drift_ppm).abs() < 1.0, "estimated {estimated} ppm, expected {drift_ppm}");
+        }
+    }
+
+    #[test]
+    fn predicts_delta() {
+        let estimate = estimate(25.0);
+
+        // 20s after the first sample, delta has drifted by 500us
+        let delta = estimate.delta_at(TimestampMicros(1_020_000_000)).unwrap();
+        assert!((delta.as_micros() - 50_500).abs() < 20, "predicted {}", delta.as_micros());
+    }
+
+    #[test]
+    fn median_before_enough_samples() {
+        let mut estimate = ClockEstimate::new();
+
+        for (index, delta) in [100, 300, 200].into_iter().enumerate() {
+            let local = TimestampMicros(1_000_000 + index as u64 * 200_000);
+            estimate.observe(local, ClockDelta::from_micros(delta));
+        }
+
+        assert_eq!(estimate.drift_ppm(), None);
+        assert_eq!(estimate.delta_at(TimestampMicros(5_000_000)).unwrap().as_micros(), 200);
+    }
+}
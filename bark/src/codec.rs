@@ -0,0 +1,301 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+use bark_protocol::crypt::CipherKey;
+use bark_protocol::packet::{Audio, AudioEncrypted, AudioOpus, Packet};
+use bark_protocol::types::{AudioPacketHeader, Capabilities, SampleFormat, SessionId};
+use bark_protocol::time::SampleDuration;
+use bark_protocol::StreamFormat;
+
+// full scale values for integer sample formats
+const S16_SCALE: f32 = 32767.0;
+const S24_SCALE: f32 = 8388607.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Uncompressed 32 bit float PCM
+    Pcm,
+    /// Uncompressed 16 bit integer PCM
+    S16LE,
+    /// Uncompressed 24 bit integer PCM
+    S24LE,
+    /// Opus compressed audio
+    Opus,
+}
+
+#[derive(Debug)]
+pub struct UnknownCodec(String);
+
+impl Display for UnknownCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown codec {:?}, expected one of: pcm, s16le, s24le, opus", self.0)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = UnknownCodec;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pcm" => Ok(Codec::Pcm),
+            "s16le" => Ok(Codec::S16LE),
+            "s24le" => Ok(Codec::S24LE),
+            "opus" => Ok(Codec::Opus),
+            _ => Err(UnknownCodec(s.to_string())),
+        }
+    }
+}
+
+impl Codec {
+    /// Capabilities a receiver must have to play a stream using this codec
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            Codec::Pcm => Capabilities::empty(),
+            Codec::S16LE | Codec::S24LE => Capabilities::AUDIO_INT_PCM,
+            Codec::Opus => Capabilities::AUDIO_OPUS,
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Pcm => write!(f, "pcm"),
+            Codec::S16LE => write!(f, "s16le"),
+            Codec::S24LE => write!(f, "s24le"),
+            Codec::Opus => write!(f, "opus"),
+        }
+    }
+}
+
+pub enum Encoder {
+    Pcm(PcmEncoder),
+    Opus(OpusEncoder),
+}
+
+pub enum Encoded {
+    Audio(Audio),
+    Opus(AudioOpus),
+    Encrypted(AudioEncrypted),
+}
+
+impl Encoded {
+    pub fn as_packet(&self) -> &Packet {
+        match self {
+            Encoded::Audio(audio) => audio.as_packet(),
+            Encoded::Opus(opus) => opus.as_packet(),
+            Encoded::Encrypted(encrypted) => encrypted.as_packet(),
+        }
+    }
+
+    pub fn header(&self) -> &AudioPacketHeader {
+        match self {
+            Encoded::Audio(audio) => audio.header(),
+            Encoded::Opus(opus) => opus.header(),
+            Encoded::Encrypted(encrypted) => encrypted.header(),
+        }
+    }
+
+    pub fn encrypt(self, key: &CipherKey) -> Encoded {
+        if let Encoded::Encrypted(_) = self {
+            return self;
+        }
+
+        let encrypted = AudioEncrypted::encrypt(self.as_packet(), key)
+            .expect("allocate AudioEncrypted packet");
+
+        Encoded::Encrypted(encrypted)
+    }
+}
+
+impl Encoder {
+    pub fn new(codec: Codec, format: StreamFormat) -> Result<Self, CodecError> {
+        match codec {
+            Codec::Pcm => Ok(Encoder::Pcm(PcmEncoder::new(SampleFormat::F32))),
+            Codec::S16LE => Ok(Encoder::Pcm(PcmEncoder::new(SampleFormat::S16LE))),
+            Codec::S24LE => Ok(Encoder::Pcm(PcmEncoder::new(SampleFormat::S24LE))),
+            Codec::Opus => Ok(Encoder::Opus(OpusEncoder::new(format)?)),
+        }
+    }
+
+    /// Encodes a full f32 audio packet for transmission
+    pub fn encode(&mut self, audio: Audio) -> Result<Encoded, CodecError> {
+        match self {
+            Encoder::Pcm(encoder) => Ok(Encoded::Audio(encoder.encode(audio))),
+            Encoder::Opus(encoder) => Ok(Encoded::Opus(encoder.encode(&audio)?)),
+        }
+    }
+}
+
+pub struct PcmEncoder {
+    format: SampleFormat,
+    rng: SmallRng,
+}
+
+impl PcmEncoder {
+    pub fn new(format: SampleFormat) -> Self {
+        PcmEncoder {
+            format,
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    pub fn encode(&mut self, audio: Audio) -> Audio {
+        let scale = match self.format {
+            SampleFormat::F32 => { return audio; }
+            SampleFormat::S16LE => S16_SCALE,
+            SampleFormat::S24LE => S24_SCALE,
+        };
+
+        let mut encoded = Audio::with_format(self.format, *audio.header())
+            .expect("allocate Audio packet");
+
+        let width = self.format.bytes_per_sample();
+        let samples = audio.buffer().iter();
+        let chunks = encoded.data_mut().chunks_exact_mut(width);
+
+        for (sample, chunk) in samples.zip(chunks) {
+            // triangular dither of +/- 1 LSB to decorrelate quantisation
+            // error from the signal
+            let dither = self.rng.gen::<f32>() - self.rng.gen::<f32>();
+            let value = (sample * scale + dither).round().clamp(-scale - 1.0, scale) as i32;
+            chunk.copy_from_slice(&value.to_le_bytes()[0..width]);
+        }
+
+        encoded
+    }
+}
+
+/// Converts an integer PCM audio packet back to f32. f32 packets are
+/// passed through as is.
+pub fn decode_pcm(audio: Audio) -> Audio {
+    let format = audio.format();
+
+    if format == SampleFormat::F32 {
+        return audio;
+    }
+
+    let mut decoded = Audio::with_format(SampleFormat::F32, *audio.header())
+        .expect("allocate Audio packet");
+
+    let width = format.bytes_per_sample();
+    let chunks = audio.data().chunks_exact(width);
+    let samples = decoded.buffer_mut().iter_mut();
+
+    for (chunk, sample) in chunks.zip(samples) {
+        *sample = match format {
+            SampleFormat::S16LE => {
+                f32::from(i16::from_le_bytes([chunk[0], chunk[1]])) / S16_SCALE
+            }
+            SampleFormat::S24LE => {
+                // place in upper 24 bits of an i32 and shift down to sign extend
+                let value = i32::from_le_bytes([0, chunk[0], chunk[1], chunk[2]]) >> 8;
+                value as f32 / S24_SCALE
+            }
+            SampleFormat::F32 => unreachable!(),
+        };
+    }
+
+    decoded
+}
+
+fn opus_channels(format: StreamFormat) -> Result<opus::Channels, CodecError> {
+    match format.channels.0 {
+        1 => Ok(opus::Channels::Mono),
+        2 => Ok(opus::Channels::Stereo),
+        _ => Err(CodecError::UnsupportedFormat(format)),
+    }
+}
+
+// opus supports 8, 12, 16, 24 and 48khz, but a packet of FRAMES_PER_PACKET
+// frames is only a valid opus frame size (2.5, 5 or 10ms) at these rates
+fn opus_sample_rate(format: StreamFormat) -> Result<u32, CodecError> {
+    match format.sample_rate.0 {
+        12000 | 24000 | 48000 => Ok(format.sample_rate.0),
+        _ => Err(CodecError::UnsupportedFormat(format)),
+    }
+}
+
+pub struct OpusEncoder {
+    encoder: opus::Encoder,
+    buffer: Box<[u8]>,
+}
+
+impl OpusEncoder {
+    pub fn new(format: StreamFormat) -> Result<Self, CodecError> {
+        let encoder = opus::Encoder::new(
+            opus_sample_rate(format)?,
+            opus_channels(format)?,
+            opus::Application::LowDelay,
+        ).map_err(CodecError::Opus)?;
+
+        Ok(OpusEncoder {
+            encoder,
+            buffer: vec![0u8; AudioOpus::MAX_DATA_LENGTH].into_boxed_slice(),
+        })
+    }
+
+    pub fn encode(&mut self, audio: &Audio) -> Result<AudioOpus, CodecError> {
+        let len = self.encoder.encode_float(audio.buffer(), &mut self.buffer)
+            .map_err(CodecError::Opus)?;
+
+        let opus = AudioOpus::new(*audio.header(), &self.buffer[0..len])
+            .expect("allocate AudioOpus packet");
+
+        Ok(opus)
+    }
+}
+
+pub struct OpusDecoder {
+    sid: SessionId,
+    format: StreamFormat,
+    decoder: opus::Decoder,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Opus(opus::Error),
+    UnsupportedFormat(StreamFormat),
+    WrongLength(SampleDuration),
+}
+
+impl OpusDecoder {
+    pub fn new(sid: SessionId, format: StreamFormat) -> Result<Self, CodecError> {
+        let decoder = opus::Decoder::new(
+            opus_sample_rate(format)?,
+            opus_channels(format)?,
+        ).map_err(CodecError::Opus)?;
+
+        Ok(OpusDecoder { sid, format, decoder })
+    }
+
+    /// Whether this decoder can decode the packet, otherwise a new decoder
+    /// should be created
+    pub fn matches(&self, packet: &AudioOpus) -> bool {
+        let header = packet.header();
+        self.sid == header.sid && self.format == header.format
+    }
+
+    pub fn decode(&mut self, packet: &AudioOpus) -> Result<Audio, CodecError> {
+        let mut buffer = [0f32; bark_protocol::MAX_SAMPLES_PER_PACKET];
+        let buffer = &mut buffer[0..self.format.samples_per_packet()];
+
+        let frames = self.decoder.decode_float(packet.data(), buffer, false)
+            .map_err(CodecError::Opus)?;
+
+        let length = SampleDuration::from_frame_count(frames as u64);
+        if length != SampleDuration::ONE_PACKET {
+            return Err(CodecError::WrongLength(length));
+        }
+
+        let mut writer = Audio::write(self.format)
+            .expect("allocate Audio packet");
+
+        writer.write(buffer);
+
+        Ok(writer.finalize(*packet.header()))
+    }
+}
@@ -0,0 +1,208 @@
+use bytemuck::Zeroable;
+
+use bark_protocol::{StreamFormat, FRAMES_PER_PACKET};
+use bark_protocol::packet::Audio;
+use bark_protocol::types::{AudioPacketHeader, SampleFormat};
+
+/// Fills in for lost packets by continuing the waveform of the last packet
+/// received, fading out if several packets in a row are lost
+pub struct Concealer {
+    channels: usize,
+    // last packet received, concealment continues on from it
+    last: Option<Audio>,
+    // packets to synthesize concealment into. concealment runs on the
+    // audio thread, so these are allocated up front and recycled
+    spare: Vec<Audio>,
+    // scratch space for the downmixed last packet when finding its period
+    mono: Vec<f32>,
+    // period of the waveform at the end of the last packet, in frames
+    period: usize,
+    // frame within the period to continue from
+    position: usize,
+    // packets concealed since the last packet received
+    count: usize,
+}
+
+impl Concealer {
+    // fade to silence over this many packets, anything longer than this is
+    // better served by silence than a repeating waveform
+    const MAX_PACKETS: usize = 4;
+
+    // number of frames at the end of the last packet matched against
+    // earlier frames to find the period of the waveform
+    const WINDOW: usize = 24;
+
+    // minimum normalised correlation for a candidate period to be used.
+    // otherwise the whole of the last packet is repeated
+    const MIN_SIMILARITY: f32 = 0.5;
+
+    // number of frames at the start of the first packet received after a
+    // loss over which concealment is crossfaded into the real audio
+    const CROSSFADE: usize = 48;
+
+    pub fn new(format: StreamFormat) -> Self {
+        let header = AudioPacketHeader {
+            format,
+            ..AudioPacketHeader::zeroed()
+        };
+
+        let spare = (0..Self::MAX_PACKETS)
+            .map(|_| Audio::with_format(SampleFormat::F32, header)
+                .expect("allocate Audio packet"))
+            .collect();
+
+        Concealer {
+            channels: usize::from(format.channels),
+            last: None,
+            spare,
+            mono: Vec::with_capacity(FRAMES_PER_PACKET),
+            period: 0,
+            position: 0,
+            count: 0,
+        }
+    }
+
+    /// Records a packet received and played, ready to conceal any loss
+    /// following it
+    pub fn observe(&mut self, packet: Audio) {
+        self.last = Some(packet);
+        self.count = 0;
+    }
+
+    /// Synthesizes a packet to play in place of a lost packet, or `None`
+    /// if there's nothing to conceal with and silence should be played
+    pub fn conceal(&mut self, seq: u64) -> Option<Audio> {
+        let last = self.last.as_ref()?;
+
+        if self.count >= Self::MAX_PACKETS {
+            return None;
+        }
+
+        let mut packet = self.spare.pop()?;
+
+        let source = last.buffer();
+        let frames = source.len() / self.channels;
+
+        if self.count == 0 {
+            self.period = find_period(source, self.channels, &mut self.mono);
+            self.position = 0;
+        }
+
+        *packet.header_mut() = AudioPacketHeader {
+            seq,
+            format: packet.header().format,
+            ..*last.header()
+        };
+
+        let total = (Self::MAX_PACKETS * frames) as f32;
+
+        for (index, frame) in packet.buffer_mut().chunks_exact_mut(self.channels).enumerate() {
+            // continue the last period of the waveform, fading out linearly
+            // across all concealed packets
+            let source = period_frame(source, self.channels, self.period, self.position);
+            let gain = 1.0 - (self.count * frames + index + 1) as f32 / total;
+
+            for (out, sample) in frame.iter_mut().zip(source) {
+                *out = sample * gain;
+            }
+
+            self.position = (self.position + 1) % self.period;
+        }
+
+        self.count += 1;
+        Some(packet)
+    }
+
+    /// Returns a packet produced by `conceal` once it's no longer needed,
+    /// so it can be reused for the next loss
+    pub fn recycle(&mut self, packet: Audio) {
+        if self.spare.len() < Self::MAX_PACKETS {
+            self.spare.push(packet);
+        }
+    }
+
+    /// Blends the continuation of concealment into the start of the first
+    /// real packet after a loss, so playback doesn't step back into it
+    pub fn crossfade(&mut self, packet: &mut Audio) {
+        if self.count == 0 {
+            return;
+        }
+
+        // reset whether or not there's anything to blend, so the packet is
+        // only crossfaded once
+        let count = std::mem::take(&mut self.count);
+
+        let Some(last) = self.last.as_ref() else {
+            return;
+        };
+
+        let source = last.buffer();
+        let frames = source.len() / self.channels;
+        let total = (Self::MAX_PACKETS * frames) as f32;
+
+        let output = packet.buffer_mut();
+        let fade = Self::CROSSFADE.min(output.len() / self.channels);
+
+        for (index, frame) in output.chunks_exact_mut(self.channels).take(fade).enumerate() {
+            let source = period_frame(source, self.channels, self.period, self.position);
+
+            // concealment carries on fading out where it left off, which
+            // is silence if it already ran to the end
+            let gain = (1.0 - (count * frames + index + 1) as f32 / total).max(0.0);
+            let mix = (index + 1) as f32 / (fade + 1) as f32;
+
+            for (out, sample) in frame.iter_mut().zip(source) {
+                *out = *out * mix + sample * gain * (1.0 - mix);
+            }
+
+            self.position = (self.position + 1) % self.period;
+        }
+    }
+}
+
+/// Returns the frame `position` frames into the last `period` frames of
+/// `buffer`
+fn period_frame(buffer: &[f32], channels: usize, period: usize, position: usize) -> &[f32] {
+    let frames = buffer.len() / channels;
+    let frame = frames - period + position;
+    &buffer[frame * channels..][..channels]
+}
+
+/// Finds the period of the waveform at the end of `buffer` by looking for
+/// the earlier stretch of audio most similar to its last few frames
+fn find_period(buffer: &[f32], channels: usize, mono: &mut Vec<f32>) -> usize {
+    mono.clear();
+    mono.extend(buffer.chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>()));
+
+    let frames = mono.len();
+    let window = Concealer::WINDOW;
+
+    if frames < window * 2 {
+        return frames;
+    }
+
+    let tail = &mono[frames - window..];
+
+    let mut best = (frames, Concealer::MIN_SIMILARITY);
+
+    for period in window..=(frames - window) {
+        let candidate = &mono[frames - window - period..frames - period];
+
+        let dot = tail.iter().zip(candidate).map(|(a, b)| a * b).sum::<f32>();
+        let energy_tail = tail.iter().map(|a| a * a).sum::<f32>();
+        let energy_candidate = candidate.iter().map(|b| b * b).sum::<f32>();
+
+        let energy = (energy_tail * energy_candidate).sqrt();
+        if energy == 0.0 {
+            continue;
+        }
+
+        let similarity = dot / energy;
+        if similarity > best.1 {
+            best = (period, similarity);
+        }
+    }
+
+    best.0
+}
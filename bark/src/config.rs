@@ -7,6 +7,7 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Config {
     multicast: Option<SocketAddr>,
+    key: Option<String>,
     #[serde(default)]
     source: Source,
     #[serde(default)]
@@ -17,11 +18,34 @@ pub struct Config {
 pub struct Source {
     device: Option<String>,
     delay_ms: Option<u64>,
+    codec: Option<String>,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    fec: Option<u32>,
+    encrypt: Option<bool>,
+    name: Option<String>,
+    title: Option<String>,
+    artist: Option<String>,
+    stream: Option<String>,
+    takeover: Option<String>,
+    priority: Option<u32>,
+    auto_delay: Option<bool>,
+    min_delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
 }
 
 #[derive(Deserialize, Default)]
 pub struct Receive {
     device: Option<String>,
+    channels: Option<String>,
+    session_timeout_ms: Option<u64>,
+    stream: Option<String>,
+    announce: Option<String>,
+    name: Option<String>,
+    offset_ms: Option<i64>,
+    adaptive_latency: Option<bool>,
+    min_latency_ms: Option<u64>,
+    max_latency_ms: Option<u64>,
 }
 
 fn set_env_option<T: ToString>(name: &str, value: Option<T>) {
@@ -32,9 +56,33 @@ fn set_env_option<T: ToString>(name: &str, value: Option<T>) {
 
 pub fn load_into_env(config: &Config) {
     set_env_option("BARK_MULTICAST", config.multicast);
+    set_env_option("BARK_KEY", config.key.as_ref());
     set_env_option("BARK_SOURCE_DEVICE", config.source.device.as_ref());
     set_env_option("BARK_SOURCE_DELAY_MS", config.source.delay_ms);
+    set_env_option("BARK_SOURCE_CODEC", config.source.codec.as_ref());
+    set_env_option("BARK_SOURCE_SAMPLE_RATE", config.source.sample_rate);
+    set_env_option("BARK_SOURCE_CHANNELS", config.source.channels);
+    set_env_option("BARK_SOURCE_FEC", config.source.fec);
+    set_env_option("BARK_SOURCE_ENCRYPT", config.source.encrypt);
+    set_env_option("BARK_SOURCE_NAME", config.source.name.as_ref());
+    set_env_option("BARK_SOURCE_TITLE", config.source.title.as_ref());
+    set_env_option("BARK_SOURCE_ARTIST", config.source.artist.as_ref());
+    set_env_option("BARK_SOURCE_STREAM", config.source.stream.as_ref());
+    set_env_option("BARK_SOURCE_TAKEOVER", config.source.takeover.as_ref());
+    set_env_option("BARK_SOURCE_PRIORITY", config.source.priority);
+    set_env_option("BARK_SOURCE_AUTO_DELAY", config.source.auto_delay);
+    set_env_option("BARK_SOURCE_MIN_DELAY_MS", config.source.min_delay_ms);
+    set_env_option("BARK_SOURCE_MAX_DELAY_MS", config.source.max_delay_ms);
     set_env_option("BARK_RECEIVE_DEVICE", config.receive.device.as_ref());
+    set_env_option("BARK_RECEIVE_CHANNELS", config.receive.channels.as_ref());
+    set_env_option("BARK_RECEIVE_SESSION_TIMEOUT_MS", config.receive.session_timeout_ms);
+    set_env_option("BARK_RECEIVE_STREAM", config.receive.stream.as_ref());
+    set_env_option("BARK_RECEIVE_ANNOUNCE", config.receive.announce.as_ref());
+    set_env_option("BARK_RECEIVE_NAME", config.receive.name.as_ref());
+    set_env_option("BARK_RECEIVE_OFFSET_MS", config.receive.offset_ms);
+    set_env_option("BARK_RECEIVE_ADAPTIVE_LATENCY", config.receive.adaptive_latency);
+    set_env_option("BARK_RECEIVE_MIN_LATENCY_MS", config.receive.min_latency_ms);
+    set_env_option("BARK_RECEIVE_MAX_LATENCY_MS", config.receive.max_latency_ms);
 }
 
 fn load_file(path: &Path) -> Option<Config> {
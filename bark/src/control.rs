@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use bark_protocol::packet::Control;
+use bark_protocol::types::{ControlFlags, ReceiverId};
+
+use crate::socket::{ProtocolSocket, Socket, SocketOpt};
+use crate::time;
+use crate::RunError;
+
+#[derive(StructOpt)]
+pub struct ControlOpt {
+    #[structopt(flatten)]
+    pub socket: SocketOpt,
+
+    #[structopt(long)]
+    /// Name of the receiver to control, as set by bark receive --name. By
+    /// default every receiver is controlled
+    pub receiver: Option<String>,
+
+    #[structopt(long, required_unless_one = &["mute", "unmute"])]
+    /// Output volume to set, in percent from 0 to 100
+    pub volume: Option<u32>,
+
+    #[structopt(long, conflicts_with = "unmute")]
+    /// Mute output
+    pub mute: bool,
+
+    #[structopt(long)]
+    /// Unmute output
+    pub unmute: bool,
+}
+
+pub fn run(opt: ControlOpt) -> Result<(), RunError> {
+    let rid = opt.receiver.as_deref()
+        .map(ReceiverId::from_name)
+        .unwrap_or(ReceiverId::broadcast());
+
+    let mut control = Control::new(rid)
+        .expect("allocate Control packet");
+
+    let data = control.data_mut();
+
+    if let Some(volume) = opt.volume {
+        if volume > 100 {
+            return Err(RunError::InvalidVolume(volume));
+        }
+
+        data.flags |= ControlFlags::SET_VOLUME;
+        data.volume = volume as f32 / 100.0;
+    }
+
+    if opt.mute {
+        data.flags |= ControlFlags::MUTE;
+    }
+
+    if opt.unmute {
+        data.flags |= ControlFlags::UNMUTE;
+    }
+
+    let auth = opt.socket.auth_key();
+
+    let socket = Socket::open(opt.socket)
+        .map_err(RunError::Listen)?;
+
+    let protocol = ProtocolSocket::new(socket, auth);
+
+    // receivers holding a key reject control packets which aren't recent,
+    // so that recorded packets can't be replayed
+    control.data_mut().sent = time::realtime_now();
+
+    // control packets set absolute values, so it's safe to send them a few
+    // times over in case of packet loss
+    for _ in 0..3 {
+        protocol.broadcast(control.as_packet())
+            .map_err(RunError::Socket)?;
+
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bark_protocol::packet::StatsReply;
+use bark_protocol::types::SessionId;
+
+use crate::socket::PeerId;
+
+/// Stream delay, adjusted from the stats receivers report so that the
+/// stream plays at the lowest delay all receivers can keep up with
+pub struct AutoDelay {
+    delay: Duration,
+    // follows `delay` in microseconds, for the audio thread which mustn't
+    // wait on a lock held by others
+    shared: Arc<AtomicU64>,
+    min: Duration,
+    max: Duration,
+    // delay most recently asked for by each receiver
+    reports: HashMap<PeerId, Report>,
+}
+
+struct Report {
+    required: Duration,
+    received: Instant,
+}
+
+impl AutoDelay {
+    // audio receivers should have buffered beyond what they need to play
+    // right now, for receivers which don't measure their own latency
+    const MIN_BUFFER: Duration = Duration::from_millis(5);
+
+    // receivers we haven't heard from in this long are forgotten
+    const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // most the delay may change in each update. receivers slew to follow
+    // small changes, where a sudden jump would have them resync
+    const MAX_INCREASE: Duration = Duration::from_millis(5);
+    const MAX_DECREASE: Duration = Duration::from_millis(1);
+
+    // only decrease the delay once it's this far above what's required,
+    // so that it doesn't wander up and down with every report
+    const HYSTERESIS: Duration = Duration::from_millis(2);
+
+    pub fn new(delay: Duration, min: Duration, max: Duration) -> Self {
+        let delay = delay.max(min).min(max);
+
+        AutoDelay {
+            delay,
+            shared: Arc::new(AtomicU64::new(delay.as_micros() as u64)),
+            min,
+            max,
+            reports: HashMap::new(),
+        }
+    }
+
+    /// Current delay in microseconds, readable without locking
+    pub fn shared_delay(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.shared)
+    }
+
+    /// Records the delay a receiver playing our stream needs, according to
+    /// the stats it reported
+    pub fn observe(&mut self, peer: PeerId, sid: SessionId, reply: &StatsReply) {
+        let data = reply.data();
+
+        if data.sid != sid {
+            // receiver is playing another stream
+            return;
+        }
+
+        let required = if let Some(latency) = data.diagnostics.requested_latency() {
+            // receiver has measured the latency it needs itself
+            Duration::try_from_secs_f64(latency).ok()
+        } else if let Some(buffer) = data.receiver.buffer_length() {
+            // otherwise aim to leave it with a little audio buffered
+            Duration::try_from_secs_f64(buffer).ok()
+                .map(|buffer| self.delay.saturating_sub(buffer) + Self::MIN_BUFFER)
+        } else {
+            None
+        };
+
+        let Some(required) = required else {
+            return;
+        };
+
+        self.reports.insert(peer, Report { required, received: Instant::now() });
+    }
+
+    /// Moves the delay a step towards what receivers need, returning the
+    /// new delay if it changed
+    pub fn update(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.reports.retain(|_, report| now.duration_since(report.received) < Self::REPORT_TIMEOUT);
+
+        let target = self.reports.values()
+            .map(|report| report.required)
+            .max()?
+            .max(self.min)
+            .min(self.max);
+
+        let delay = if target > self.delay {
+            self.delay + (target - self.delay).min(Self::MAX_INCREASE)
+        } else if target + Self::HYSTERESIS < self.delay {
+            self.delay - (self.delay - target).min(Self::MAX_DECREASE)
+        } else {
+            return None;
+        };
+
+        self.delay = delay;
+        self.shared.store(delay.as_micros() as u64, Ordering::Relaxed);
+        Some(delay)
+    }
+}
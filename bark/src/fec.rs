@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+
+use bark_protocol::buffer::PacketBuffer;
+use bark_protocol::packet::{AudioFec, Packet, PacketKind, MAX_FEC_GROUP_SIZE};
+use bark_protocol::types::{AudioPacketHeader, FecPacketHeader, SessionId};
+
+/// Accumulates XOR parity over groups of consecutive audio packets
+pub struct FecEncoder {
+    group_size: u32,
+    header: Option<FecPacketHeader>,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u32) -> Self {
+        assert!(group_size > 0 && group_size <= MAX_FEC_GROUP_SIZE);
+
+        FecEncoder {
+            group_size,
+            header: None,
+            parity: Vec::new(),
+        }
+    }
+
+    /// Adds a sent audio packet to the current group, returning a fec
+    /// packet once the group is complete
+    pub fn push(&mut self, audio: &AudioPacketHeader, packet: &Packet) -> Option<AudioFec> {
+        // start a new group if this packet does not directly follow the
+        // last packet in the current one
+        let continues = self.header.as_ref().map(|header| {
+            header.sid == audio.sid && header.seq + u64::from(header.count) == audio.seq
+        });
+
+        if continues != Some(true) {
+            self.header = Some(FecPacketHeader {
+                sid: audio.sid,
+                seq: audio.seq,
+                count: 0,
+                length: 0,
+            });
+            self.parity.clear();
+        }
+
+        let header = self.header.as_mut().unwrap();
+        let bytes = packet.frame();
+
+        xor_into(&mut self.parity, bytes);
+        header.count += 1;
+        header.length ^= bytes.len() as u32;
+
+        if header.count < self.group_size {
+            return None;
+        }
+
+        let header = self.header.take().unwrap();
+
+        let fec = AudioFec::new(header, &self.parity)
+            .expect("allocate AudioFec packet");
+
+        self.parity.clear();
+        Some(fec)
+    }
+}
+
+struct Received {
+    sid: SessionId,
+    seq: u64,
+    bytes: Vec<u8>,
+}
+
+/// Remembers recently received audio packets so that a single packet lost
+/// from a fec group can be reconstructed
+pub struct FecDecoder {
+    history: VecDeque<Received>,
+}
+
+impl FecDecoder {
+    // fec packets are sent after the last packet in their group, so two
+    // groups worth of history is plenty even with some reordering
+    const HISTORY: usize = 2 * MAX_FEC_GROUP_SIZE as usize;
+
+    pub fn new() -> Self {
+        FecDecoder {
+            history: VecDeque::with_capacity(Self::HISTORY),
+        }
+    }
+
+    /// Records a received audio packet, call with the packet as it was
+    /// received before any conversion
+    pub fn observe(&mut self, header: &AudioPacketHeader, packet: &Packet) {
+        if packet.is_rewritten() {
+            // bytes differ from those the parity was computed over
+            return;
+        }
+
+        if self.find(header.sid, header.seq).is_some() {
+            return;
+        }
+
+        if self.history.len() == Self::HISTORY {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(Received {
+            sid: header.sid,
+            seq: header.seq,
+            bytes: packet.frame().to_vec(),
+        });
+    }
+
+    /// Reconstructs the lost packet covered by a fec packet, if exactly one
+    /// packet from its group is missing
+    pub fn recover(&self, fec: &AudioFec) -> Option<PacketKind> {
+        let header = fec.header();
+        let group = header.seq..header.seq.saturating_add(u64::from(header.count));
+
+        let mut missing = None;
+        let mut parity = fec.parity().to_vec();
+        let mut length = header.length;
+
+        for seq in group {
+            match self.find(header.sid, seq) {
+                Some(received) => {
+                    xor_into(&mut parity, &received.bytes);
+                    length ^= received.bytes.len() as u32;
+                }
+                None if missing.is_none() => {
+                    missing = Some(seq);
+                }
+                None => {
+                    // more than one packet lost, can't recover
+                    return None;
+                }
+            }
+        }
+
+        // nothing to do if we received every packet
+        let seq = missing?;
+
+        let length = usize::try_from(length).ok()?;
+        if length > parity.len() {
+            return None;
+        }
+
+        let mut buffer = PacketBuffer::allocate(length).ok()?;
+        buffer.as_bytes_mut().copy_from_slice(&parity[0..length]);
+
+        // parity is computed over packets without their authentication
+        // codes. the recovered packet is as trustworthy as the fec packet
+        // and the packets it was recovered from, which were all checked
+        let recovered = Packet::from_buffer(buffer)?.parse(None)?;
+
+        // sanity check that we recovered what we expected to
+        let recovered_header = match &recovered {
+            PacketKind::Audio(audio) => audio.header(),
+            PacketKind::AudioOpus(opus) => opus.header(),
+            PacketKind::AudioEncrypted(encrypted) => encrypted.header(),
+            _ => { return None; }
+        };
+
+        if recovered_header.sid != header.sid || recovered_header.seq != seq {
+            return None;
+        }
+
+        Some(recovered)
+    }
+
+    fn find(&self, sid: SessionId, seq: u64) -> Option<&Received> {
+        self.history.iter().find(|received| received.sid == sid && received.seq == seq)
+    }
+}
+
+fn xor_into(parity: &mut Vec<u8>, bytes: &[u8]) {
+    if parity.len() < bytes.len() {
+        parity.resize(bytes.len(), 0);
+    }
+
+    for (p, b) in parity.iter_mut().zip(bytes) {
+        *p ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bark_protocol::packet::Audio;
+    use bark_protocol::types::StreamId;
+    use bark_protocol::StreamFormat;
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    fn audio(seq: u64) -> Audio {
+        let format = StreamFormat::DEFAULT;
+        let header = AudioPacketHeader {
+            sid: SessionId(1),
+            seq,
+            stream: StreamId::UNNAMED,
+            format,
+            ..AudioPacketHeader::zeroed()
+        };
+
+        let samples = (0..format.samples_per_packet())
+            .map(|i| (seq as usize * 1000 + i) as f32)
+            .collect::<Vec<_>>();
+
+        let mut writer = Audio::write(format).unwrap();
+        writer.write(&samples);
+        writer.finalize(header)
+    }
+
+    #[test]
+    fn recovers_one_lost_packet() {
+        let mut encoder = FecEncoder::new(4);
+        let mut decoder = FecDecoder::new();
+
+        let packets = (10..14).map(audio).collect::<Vec<_>>();
+        let mut fec = None;
+
+        for (index, packet) in packets.iter().enumerate() {
+            fec = encoder.push(packet.header(), packet.as_packet());
+
+            // lose the third packet
+            if index != 2 {
+                decoder.observe(packet.header(), packet.as_packet());
+            }
+        }
+
+        let fec = fec.expect("fec packet after complete group");
+
+        let Some(PacketKind::Audio(recovered)) = decoder.recover(&fec) else {
+            panic!("failed to recover lost packet");
+        };
+
+        assert_eq!(recovered.header().seq, 12);
+        assert_eq!(recovered.as_packet().frame(), packets[2].as_packet().frame());
+    }
+
+    #[test]
+    fn cannot_recover_two_lost_packets() {
+        let mut encoder = FecEncoder::new(4);
+        let mut decoder = FecDecoder::new();
+
+        let mut fec = None;
+
+        for seq in 10..14 {
+            let packet = audio(seq);
+            fec = encoder.push(packet.header(), packet.as_packet());
+
+            if seq < 12 {
+                decoder.observe(packet.header(), packet.as_packet());
+            }
+        }
+
+        assert!(decoder.recover(&fec.unwrap()).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Tracks how long audio packets take to arrive and how many arrive too
+/// late to be played, to work out the lowest latency a stream can be
+/// played at without running out of audio
+pub struct Jitter {
+    // recent transit times in microseconds, from the source sending a
+    // packet to us receiving it
+    transit: VecDeque<i64>,
+    // whether each recent packet arrived too late to be played
+    late: VecDeque<bool>,
+}
+
+impl Jitter {
+    // number of recent packets considered
+    const WINDOW: usize = 256;
+
+    // transit times needed before estimating a safe latency
+    const MIN_SAMPLES: usize = 32;
+
+    // fraction of packets which must arrive in time at the safe latency
+    const PERCENTILE: f64 = 0.99;
+
+    // extra latency on top of the measured transit time, to allow for
+    // variation not yet seen
+    const HEADROOM: Duration = Duration::from_millis(2);
+
+    pub fn new() -> Self {
+        Jitter {
+            transit: VecDeque::with_capacity(Self::WINDOW),
+            late: VecDeque::with_capacity(Self::WINDOW),
+        }
+    }
+
+    /// Records the transit time of a packet, as measured against the
+    /// source's clock
+    pub fn observe_transit(&mut self, transit_usec: i64) {
+        push_bounded(&mut self.transit, transit_usec);
+    }
+
+    /// Records whether a packet arrived in time to be played
+    pub fn observe_arrival(&mut self, late: bool) {
+        push_bounded(&mut self.late, late);
+    }
+
+    /// Fraction of recent packets which arrived too late to be played
+    pub fn late_rate(&self) -> Option<f64> {
+        if self.late.is_empty() {
+            return None;
+        }
+
+        let late = self.late.iter().filter(|late| **late).count();
+        Some(late as f64 / self.late.len() as f64)
+    }
+
+    /// Time from the source sending a packet to us receiving it which all
+    /// but the slowest few recent packets would have made. The latency the
+    /// stream can be played at must also allow for the length of each
+    /// packet and our output latency
+    pub fn safe_latency(&self) -> Option<Duration> {
+        if self.transit.len() < Self::MIN_SAMPLES {
+            return None;
+        }
+
+        let mut transit = self.transit.iter().copied().collect::<Vec<_>>();
+        transit.sort();
+
+        let index = ((transit.len() - 1) as f64 * Self::PERCENTILE).round() as usize;
+        let transit = Duration::from_micros(u64::try_from(transit[index]).unwrap_or(0));
+
+        Some(transit + Self::HEADROOM)
+    }
+}
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, value: T) {
+    if queue.len() == Jitter::WINDOW {
+        queue.pop_front();
+    }
+
+    queue.push_back(value);
+}
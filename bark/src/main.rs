@@ -1,10 +1,20 @@
 mod audio;
+mod channels;
+mod clock;
+mod codec;
+mod conceal;
 mod config;
+mod control;
+mod delay;
+mod fec;
+mod jitter;
+mod metadata;
 mod receive;
 mod resample;
 mod socket;
 mod stats;
 mod stream;
+mod takeover;
 mod thread;
 mod time;
 mod util;
@@ -18,6 +28,7 @@ enum Opt {
     Stream(stream::StreamOpt),
     Receive(receive::ReceiveOpt),
     Stats(stats::StatsOpt),
+    Control(control::ControlOpt),
 }
 
 #[derive(Debug)]
@@ -29,6 +40,11 @@ pub enum RunError {
     BuildStream(cpal::BuildStreamError),
     Stream(cpal::PlayStreamError),
     Socket(std::io::Error),
+    InvalidStreamFormat(bark_protocol::StreamFormat),
+    Codec(codec::CodecError),
+    InvalidFecGroupSize(u32),
+    EncryptWithoutKey,
+    InvalidVolume(u32),
 }
 
 fn main() -> Result<(), ExitCode> {
@@ -42,6 +58,7 @@ fn main() -> Result<(), ExitCode> {
         Opt::Stream(opt) => stream::run(opt),
         Opt::Receive(opt) => receive::run(opt),
         Opt::Stats(opt) => stats::run(opt),
+        Opt::Control(opt) => control::run(opt),
     };
 
     result.map_err(|err| {
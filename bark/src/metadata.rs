@@ -0,0 +1,72 @@
+use std::fmt::{self, Display};
+
+use bark_protocol::packet::Metadata;
+use bark_protocol::types::{MetadataPacket, SessionId};
+
+/// Human readable description of a stream and what it is currently playing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamMetadata {
+    pub name: String,
+    pub title: String,
+    pub artist: String,
+}
+
+#[derive(Debug)]
+pub struct InvalidControl(String);
+
+impl Display for InvalidControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid metadata control {:?}, expected name=, title=, or artist= followed by a value", self.0)
+    }
+}
+
+impl StreamMetadata {
+    pub fn from_packet(packet: &MetadataPacket) -> Self {
+        StreamMetadata {
+            name: packet.name().to_string(),
+            title: packet.title().to_string(),
+            artist: packet.artist().to_string(),
+        }
+    }
+
+    pub fn to_packet(&self, sid: SessionId) -> Metadata {
+        let mut packet = Metadata::new(sid)
+            .expect("allocate Metadata packet");
+
+        let data = packet.data_mut();
+        data.set_name(&self.name);
+        data.set_title(&self.title);
+        data.set_artist(&self.artist);
+
+        packet
+    }
+
+    /// Applies a control line of the form `field=value`, as read from
+    /// stdin by `bark stream --metadata-stdin`
+    pub fn apply_control(&mut self, line: &str) -> Result<(), InvalidControl> {
+        let Some((field, value)) = line.split_once('=') else {
+            return Err(InvalidControl(line.to_string()));
+        };
+
+        let value = value.trim().to_string();
+
+        match field.trim() {
+            "name" => self.name = value,
+            "title" => self.title = value,
+            "artist" => self.artist = value,
+            _ => { return Err(InvalidControl(line.to_string())); }
+        }
+
+        Ok(())
+    }
+
+    /// Now playing description, if there is one
+    pub fn now_playing(&self) -> Option<String> {
+        match (self.artist.is_empty(), self.title.is_empty()) {
+            (true, true) => None,
+            (true, false) => Some(self.title.clone()),
+            (false, true) => Some(self.artist.clone()),
+            (false, false) => Some(format!("{} - {}", self.artist, self.title)),
+        }
+    }
+}
@@ -1,29 +1,103 @@
 use std::array;
 use std::collections::VecDeque;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bytemuck::Zeroable;
 use cpal::OutputCallbackInfo;
 use cpal::traits::{HostTrait, DeviceTrait};
 use structopt::StructOpt;
 
-use bark_protocol::SampleRate;
+use bark_protocol::{ChannelCount, SampleRate, StreamFormat};
 use bark_protocol::time::{Timestamp, SampleDuration, TimestampDelta, ClockDelta};
-use bark_protocol::types::{SessionId, ReceiverId, TimePhase};
-use bark_protocol::types::stats::receiver::{ReceiverStats, StreamStatus};
-use bark_protocol::packet::{Audio, Time, PacketKind, StatsReply};
-
+use bark_protocol::types::{AudioPacketHeader, ControlFlags, SessionId, StreamId, ReceiverId, TimePhase, ProtocolInfo, TimestampMicros};
+use bark_protocol::types::stats::receiver::{ReceiverDiagnostics, ReceiverStats, StreamStatus};
+use bark_protocol::crypt::CipherKey;
+use bark_protocol::packet::{Audio, AudioEncrypted, AudioFec, AudioOpus, Control, Goodbye, Metadata, Nack, Time, PacketKind, StatsReply};
+
+use crate::channels::{ChannelMap, ChannelMix};
+use crate::clock::{ClockEstimate, ClockFilter};
+use crate::codec::{self, OpusDecoder};
+use crate::conceal::Concealer;
+use crate::fec::FecDecoder;
+use crate::jitter::Jitter;
+use crate::metadata::StreamMetadata;
 use crate::resample::Resampler;
 use crate::socket::{ProtocolSocket, Socket, SocketOpt};
 use crate::{util, time, stats};
 use crate::RunError;
 
+// length of the ramps applied when audio starts or stops
+const FADE_DURATION: Duration = Duration::from_millis(5);
+
+// packets older than this by the sender's clock are taken to be replays.
+// well beyond any latency audio could still be played at
+const MAX_PACKET_AGE: Duration = Duration::from_secs(2);
+
 pub struct Receiver {
     opt: ReceiveOpt,
+    output: StreamFormat,
     stats: ReceiverStats,
+    diagnostics: ReceiverDiagnostics,
+    // the stream we play, followed by any announcement streams which
+    // interrupt it
+    slots: Vec<Slot>,
+    // slot we're currently playing audio from
+    playing: Option<usize>,
+    // time from filling the output buffer to it being played, as of the
+    // last fill
+    output_latency: Duration,
+    // slot we were playing before the last change in selection, and the
+    // fade across from it
+    fading_from: Option<usize>,
+    fade: Fade,
+    fec: FecDecoder,
+    cipher: Option<CipherKey>,
+    // whether packets are authenticated with a key, in which case audio
+    // which isn't fresh can only be a replay of recorded packets
+    authenticated: bool,
+    // last session we warned about failing to decrypt, so that a stream
+    // encrypted with another key doesn't flood the terminal
+    warned_decrypt: Option<SessionId>,
+    // retransmission request for packets found missing, waiting to be sent
+    nack: Option<Nack>,
+    // output volume as set by bark control
+    volume: f32,
+    muted: bool,
+    // gain applied at the end of the last fill, changes in volume are
+    // ramped from here to avoid clicks
+    gain: f32,
+}
+
+/// A place for one stream in the receiver. Each slot plays at most one
+/// stream at a time, and a new session of the same stream takes it over
+struct Slot {
+    // stream played in this slot, or None to play any stream not claimed
+    // by another slot
+    filter: Option<StreamId>,
     stream: Option<Stream>,
-    queue: VecDeque<QueueEntry>,
+    // stream just taken over by a new stream, kept until it has been
+    // faded out
+    previous: Option<Stream>,
+    fade: Fade,
+    opus: Option<OpusDecoder>,
+    // last session to say goodbye in this slot. packets still arriving
+    // from it once it has been reset must not start it over again
+    ended_sid: Option<SessionId>,
+}
+
+impl Slot {
+    pub fn new(filter: Option<StreamId>) -> Self {
+        Slot {
+            filter,
+            stream: None,
+            previous: None,
+            fade: Fade::finished(),
+            opus: None,
+            ended_sid: None,
+        }
+    }
 }
 
 struct QueueEntry {
@@ -31,50 +105,351 @@ struct QueueEntry {
     pts: Option<Timestamp>,
     consumed: SampleDuration,
     packet: Option<Audio>,
+    // set once we've tried to conceal the loss of this packet
+    concealed: bool,
 }
 
 impl QueueEntry {
-    pub fn as_full_buffer(&self) -> &[f32] {
+    pub fn as_full_buffer(&self, channels: ChannelCount) -> &[f32] {
+        let len = SampleDuration::ONE_PACKET.as_buffer_offset(channels);
+
         self.packet.as_ref()
             .map(|packet| packet.buffer())
-            .unwrap_or(&[0f32; bark_protocol::SAMPLES_PER_PACKET])
+            .unwrap_or(&[0f32; bark_protocol::MAX_SAMPLES_PER_PACKET][0..len])
     }
 }
 
 struct Stream {
     sid: SessionId,
+    // format as advertised by the sender. audio in the queue has been
+    // converted to the output channel count, but remains at the stream
+    // sample rate
+    format: StreamFormat,
+    // priority advertised by the sender, the highest priority stream is
+    // played when several are available
+    priority: u32,
     start_seq: u64,
     sync: bool,
+    // set when the source has said goodbye, the stream plays out what's
+    // left in its queue and is then reset
+    ended: bool,
+    // when we last received audio for this stream, used to expire it if
+    // the source goes away without saying goodbye
+    last_packet: Instant,
+    queue: VecDeque<QueueEntry>,
+    // output of the last fill, kept so we can fade between streams
+    buffer: Vec<f32>,
+    // gain at the end of the last fill. audio is faded in from silence and
+    // out before running out, to avoid pops
+    level: f32,
+    stats: ReceiverStats,
+    diagnostics: ReceiverDiagnostics,
+    metadata: Option<StreamMetadata>,
+    concealer: Concealer,
+    jitter: Jitter,
+    channel_mix: ChannelMix,
     resampler: Resampler,
     rate_adjust: RateAdjust,
     latency: Aggregate<Duration>,
-    clock_delta: Aggregate<ClockDelta>,
+    clock_filter: ClockFilter,
+    clock: ClockEstimate,
 }
 
 impl Stream {
-    pub fn start_from_packet(audio: &Audio) -> Self {
-        let resampler = Resampler::new();
+    pub fn start_from_packet(audio: &Audio, output: StreamFormat, opt: &ReceiveOpt) -> Self {
+        let format = audio.stream_format();
+        let channel_mix = opt.channels.mix(format.channels, output.channels);
+        let resampler = Resampler::new(output.channels, format.sample_rate, output.sample_rate);
+
+        let mut stats = ReceiverStats::new();
+        stats.clear();
 
         Stream {
             sid: audio.header().sid,
+            format,
+            priority: audio.header().priority,
             start_seq: audio.header().seq,
             sync: false,
+            ended: false,
+            last_packet: Instant::now(),
+            queue: VecDeque::with_capacity(opt.max_seq_gap),
+            buffer: Vec::new(),
+            level: 0.0,
+            stats,
+            diagnostics: ReceiverDiagnostics::new(),
+            metadata: None,
+            concealer: Concealer::new(StreamFormat::new(format.sample_rate, output.channels)),
+            jitter: Jitter::new(),
+            channel_mix,
             resampler,
-            rate_adjust: RateAdjust::new(),
+            rate_adjust: RateAdjust::new(format.sample_rate),
             latency: Aggregate::new(),
-            clock_delta: Aggregate::new(),
+            clock_filter: ClockFilter::new(),
+            clock: ClockEstimate::new(),
         }
     }
 
-    pub fn adjust_pts(&self, pts: Timestamp) -> Option<Timestamp> {
-        self.clock_delta.median().map(|delta| {
-            pts.adjust(TimestampDelta::from_clock_delta_lossy(delta))
+    /// Converts a pts on the source's clock to ours, as of our time `now`
+    pub fn adjust_pts(&self, pts: Timestamp, now: TimestampMicros) -> Option<Timestamp> {
+        self.clock.delta_at(now).map(|delta| {
+            pts.adjust(TimestampDelta::from_clock_delta_lossy(delta, self.format.sample_rate))
         })
     }
 
+    /// Time since the source sent a packet with the given dts, as of our
+    /// time `now`, once we know the source's clock
+    pub fn packet_age(&self, dts: TimestampMicros, now: TimestampMicros) -> Option<Duration> {
+        let delta = self.clock.delta_at(now)?;
+        let source_now = now.0 as i64 - delta.as_micros();
+        let age = u64::try_from(source_now - dts.0 as i64).unwrap_or(0);
+        Some(Duration::from_micros(age))
+    }
+
     pub fn network_latency(&self) -> Option<Duration> {
         self.latency.median()
     }
+
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        self.last_packet.elapsed() >= timeout
+    }
+
+    /// Whether the source has said goodbye and we've played all of the
+    /// audio it sent
+    pub fn is_finished(&self) -> bool {
+        self.ended && self.queue.is_empty()
+    }
+
+    /// Records whether a packet arrived in time to be played, and updates
+    /// the stats that depend on arrival times
+    fn observe_arrival(&mut self, late: bool) {
+        self.jitter.observe_arrival(late);
+
+        if let Some(rate) = self.jitter.late_rate() {
+            self.diagnostics.set_late_packet_rate(rate);
+        }
+    }
+
+    /// Lowest latency this stream can be played at without running out of
+    /// audio, if adaptive latency is enabled and enough packets have been
+    /// seen to tell
+    fn requested_latency(&self, opt: &ReceiveOpt, output_latency: Duration) -> Option<Duration> {
+        if !opt.adaptive_latency {
+            return None;
+        }
+
+        let transit = self.jitter.safe_latency()?;
+
+        // packets must arrive in time for all of their audio to be played,
+        // and audio is filled ahead of being played by the output latency
+        let packet = SampleDuration::ONE_PACKET.to_std_duration_lossy(self.format.sample_rate);
+        let latency = transit + packet + output_latency;
+
+        Some(latency
+            .max(Duration::from_millis(opt.min_latency_ms))
+            .min(Duration::from_millis(opt.max_latency_ms)))
+    }
+
+    /// Fills our buffer with the next `len` samples of output
+    pub fn fill(&mut self, len: usize, pts: TimestampMicros, output: StreamFormat) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(len, 0f32);
+        let audible = self.fill_from_queue(&mut buffer, pts, output);
+        self.apply_fades(&mut buffer, audible, output);
+        self.buffer = buffer;
+    }
+
+    /// Ramps up audio which follows silence, and ramps down audio which
+    /// runs out before the end of the buffer
+    fn apply_fades(&mut self, data: &mut [f32], audible: Option<Range<usize>>, output: StreamFormat) {
+        let Some(audible) = audible else {
+            self.level = 0.0;
+            return;
+        };
+
+        let channels = usize::from(output.channels);
+        let step = 1.0 / fade_frames(output) as f32;
+
+        let underrun = audible.end < data.len();
+        let frames = (audible.end - audible.start) / channels;
+
+        for (index, frame) in data[audible].chunks_exact_mut(channels).enumerate() {
+            self.level = f32::min(self.level + step, 1.0);
+
+            let mut gain = self.level;
+            if underrun {
+                // fade out so that we reach silence as the audio runs out
+                gain = f32::min(gain, (frames - index - 1) as f32 * step);
+            }
+
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+
+        if underrun {
+            self.level = 0.0;
+        }
+    }
+
+    /// Fills `data` from the queue, returning the range of `data` which was
+    /// filled with audio rather than silence
+    fn fill_from_queue(&mut self, mut data: &mut [f32], pts: TimestampMicros, output: StreamFormat) -> Option<Range<usize>> {
+        let channels = output.channels;
+        let len = data.len();
+
+        // complete frames only:
+        assert!(data.len() % usize::from(channels) == 0);
+
+        // all timing is done at the stream's sample rate, durations in the
+        // output buffer must be converted from the output rate
+        let rate = self.format.sample_rate;
+        let pts = Timestamp::from_micros_lossy(pts, rate);
+
+        let data_duration = SampleDuration::from_buffer_offset(data.len(), channels)
+            .convert_rate_lossy(output.sample_rate, rate);
+
+        let real_ts_after_fill = pts.add(data_duration);
+
+        // sync up to stream if necessary:
+        if !self.sync {
+            loop {
+                let Some(front) = self.queue.front_mut() else {
+                    // nothing at front of queue?
+                    data.fill(0f32);
+                    return None;
+                };
+
+                let Some(front_pts) = front.pts else {
+                    // haven't received enough info to adjust pts of queue
+                    // front yet, just pop and ignore it
+                    self.queue.pop_front();
+                    // and output silence for this part:
+                    data.fill(0f32);
+                    return None;
+                };
+
+                if pts > front_pts {
+                    // frame has already begun, we are late
+                    let late = pts.duration_since(front_pts);
+
+                    if late >= SampleDuration::ONE_PACKET {
+                        // we are late by more than a packet, skip to the next
+                        self.queue.pop_front();
+                        continue;
+                    }
+
+                    // partially consume this packet to sync up
+                    front.consumed = late;
+
+                    // we are synced
+                    self.sync = true;
+                    self.stats.set_stream(StreamStatus::Sync);
+                    break;
+                }
+
+                // otherwise we are early
+                let early = front_pts.duration_since(pts);
+
+                if early >= data_duration {
+                    // we are early by more than what was asked of us in this
+                    // call, fill with zeroes and return
+                    data.fill(0f32);
+                    return None;
+                }
+
+                // we are early, but not an entire packet timing's early
+                // partially output some zeroes
+                let zero_count = early
+                    .convert_rate_lossy(rate, output.sample_rate)
+                    .as_buffer_offset(channels);
+                data[0..zero_count].fill(0f32);
+                data = &mut data[zero_count..];
+
+                // then mark ourselves as synced and fall through to regular processing
+                self.sync = true;
+                self.stats.set_stream(StreamStatus::Sync);
+                break;
+            }
+        }
+
+        let audible_start = len - data.len();
+        let mut stream_ts = None;
+
+        // copy data to out
+        while data.len() > 0 {
+            let Some(front) = self.queue.front_mut() else {
+                let audible_end = len - data.len();
+                data.fill(0f32);
+                self.stats.set_stream(StreamStatus::Miss);
+                return Some(audible_start..audible_end);
+            };
+
+            if front.packet.is_none() && !front.concealed {
+                // packet lost, fill the gap with a continuation of what
+                // came before rather than silence
+                front.packet = self.concealer.conceal(front.seq);
+                front.concealed = true;
+
+                if front.packet.is_some() {
+                    self.diagnostics.add_concealed_packet();
+                }
+            } else if !front.concealed && front.consumed == SampleDuration::zero() {
+                // first real audio after a loss, blend out of concealment
+                if let Some(packet) = front.packet.as_mut() {
+                    self.concealer.crossfade(packet);
+                }
+            }
+
+            let buffer = front.as_full_buffer(channels);
+            let buffer_offset = front.consumed.as_buffer_offset(channels);
+            let buffer_remaining = buffer.len() - buffer_offset;
+
+            let copy_count = std::cmp::min(data.len(), buffer_remaining);
+            let buffer_copy_end = buffer_offset + copy_count;
+
+            let input = &buffer[buffer_offset..buffer_copy_end];
+            let output = &mut data[0..copy_count];
+            let result = self.resampler.process_interleaved(input, output)
+                .expect("resample error!");
+
+            data = &mut data[result.output_written.as_buffer_offset(channels)..];
+            front.consumed = front.consumed.add(result.input_read);
+
+            stream_ts = front.pts.map(|front_pts| front_pts.add(front.consumed));
+
+            // pop packet if fully consumed
+            if front.consumed == SampleDuration::ONE_PACKET {
+                let entry = self.queue.pop_front().unwrap();
+
+                match (entry.packet, entry.concealed) {
+                    (Some(packet), false) => self.concealer.observe(packet),
+                    (Some(packet), true) => self.concealer.recycle(packet),
+                    (None, _) => {}
+                }
+            }
+        }
+
+        if let Some(stream_ts) = stream_ts {
+            let rate = self.rate_adjust.sample_rate(Timing {
+                real: real_ts_after_fill,
+                play: stream_ts,
+            });
+
+            let _ = self.resampler.set_input_rate(rate.0);
+
+            if self.rate_adjust.slew() {
+                self.stats.set_stream(StreamStatus::Slew);
+            } else {
+                self.stats.set_stream(StreamStatus::Sync);
+            }
+
+            self.stats.set_audio_latency(real_ts_after_fill, stream_ts, rate);
+        }
+
+        self.stats.set_buffer_length(self.queue.iter()
+            .map(|entry| SampleDuration::ONE_PACKET.sub(entry.consumed))
+            .fold(SampleDuration::zero(), |cum, dur| cum.add(dur)), rate);
+
+        Some(audible_start..len)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -84,70 +459,210 @@ pub struct ClockInfo {
 }
 
 impl Receiver {
-    pub fn new(opt: ReceiveOpt) -> Self {
-        let queue = VecDeque::with_capacity(opt.max_seq_gap);
+    pub fn new(opt: ReceiveOpt, output: StreamFormat) -> Self {
+        let cipher = opt.socket.cipher_key();
+        let authenticated = opt.socket.auth_key().is_some();
+
+        let slots = std::iter::once(opt.stream_filter())
+            .chain(opt.announce_streams().into_iter().map(Some))
+            .map(Slot::new)
+            .collect();
 
         Receiver {
             opt,
-            stream: None,
-            queue,
+            output,
+            slots,
+            playing: None,
+            output_latency: Duration::ZERO,
+            fading_from: None,
+            fade: Fade::finished(),
+            fec: FecDecoder::new(),
+            cipher,
+            authenticated,
+            warned_decrypt: None,
+            nack: None,
+            volume: 1.0,
+            muted: false,
+            gain: 1.0,
             stats: ReceiverStats::new(),
+            diagnostics: ReceiverDiagnostics::new(),
+        }
+    }
+
+    pub fn set_output_latency(&mut self, latency: Duration) {
+        self.output_latency = latency;
+    }
+
+    pub fn stats(&self) -> ReceiverStats {
+        let mut stats = self.stats;
+        stats.set_volume(self.volume, self.muted);
+        stats.set_offset_ms(self.opt.offset_ms);
+        stats
+    }
+
+    pub fn diagnostics(&self) -> ReceiverDiagnostics {
+        let mut diagnostics = self.diagnostics;
+
+        // working out the requested latency sorts recent transit times, so
+        // it's left until stats are asked for rather than done per packet
+        let latency = self.playing_stream()
+            .and_then(|stream| stream.requested_latency(&self.opt, self.output_latency));
+
+        if let Some(latency) = latency {
+            diagnostics.set_requested_latency(latency);
         }
+
+        diagnostics
     }
 
-    pub fn stats(&self) -> &ReceiverStats {
-        &self.stats
+    fn playing_stream(&self) -> Option<&Stream> {
+        self.slots.get(self.playing?)?.stream.as_ref()
     }
 
     pub fn current_session(&self) -> Option<SessionId> {
-        self.stream.as_ref().map(|s| s.sid)
+        self.playing_stream().map(|s| s.sid)
     }
 
-    pub fn receive_time(&mut self, packet: Time) {
-        let Some(stream) = self.stream.as_mut() else {
-            // no stream, nothing we can do with a time packet
-            return;
-        };
+    /// Takes any pending retransmission request, to be sent to the stream
+    /// source
+    pub fn take_nack(&mut self) -> Option<Nack> {
+        self.nack.take()
+    }
 
-        if stream.sid != packet.data().sid {
-            // not relevant to our stream, ignore
-            return;
+    /// Slot playing the given stream, announcement streams are matched
+    /// first so that they are never played as the main stream
+    fn slot_for(&self, stream: StreamId) -> Option<usize> {
+        self.slots.iter()
+            .position(|slot| slot.filter == Some(stream))
+            .or_else(|| self.slots[0].filter.is_none().then_some(0))
+    }
+
+    fn is_our_stream(&self, header: &AudioPacketHeader) -> bool {
+        self.slot_for(header.stream).is_some()
+    }
+
+    fn session_stream_mut(&mut self, sid: SessionId) -> Option<&mut Stream> {
+        self.slots.iter_mut()
+            .filter_map(|slot| slot.stream.as_mut())
+            .find(|stream| stream.sid == sid)
+    }
+
+    fn session_timeout(&self) -> Duration {
+        Duration::from_millis(self.opt.session_timeout_ms)
+    }
+
+    /// Whether a packet from the given session belongs to a previous stream
+    /// in the slot and should be ignored. Any session is accepted once the
+    /// current one has expired, in case the new source's clock is behind
+    /// the old one's
+    fn is_previous_session(&self, slot: usize, sid: SessionId) -> bool {
+        match self.slots[slot].stream.as_ref() {
+            Some(stream) => sid < stream.sid && !stream.is_expired(self.session_timeout()),
+            None => false,
+        }
+    }
+
+    fn reset_stream(&mut self, slot: usize) {
+        let slot = &mut self.slots[slot];
+        slot.stream = None;
+        slot.previous = None;
+        slot.opus = None;
+
+        if self.slots.iter().all(|slot| slot.stream.is_none()) {
+            self.stats.clear();
+            self.stats.set_stream(StreamStatus::Idle);
+            self.diagnostics = ReceiverDiagnostics::new();
         }
+    }
 
-        let stream_1_usec = packet.data().stream_1.0;
-        let stream_3_usec = packet.data().stream_3.0;
+    /// Slot to play from, the highest priority stream which is still alive.
+    /// Streams which have synced are preferred, so we keep playing the
+    /// current stream until a new one is ready. Announcement streams win
+    /// ties with the main stream
+    fn select_slot(&self) -> Option<usize> {
+        let timeout = self.session_timeout();
+
+        self.slots.iter()
+            .enumerate()
+            .filter_map(|(index, slot)| Some((index, slot.stream.as_ref()?)))
+            .filter(|(_, stream)| !stream.is_finished() && !stream.is_expired(timeout))
+            .max_by_key(|(index, stream)| (stream.sync, stream.priority, *index))
+            .map(|(index, _)| index)
+    }
+
+    pub fn receive_time(&mut self, packet: Time) {
+        let Some(stream) = self.session_stream_mut(packet.data().sid) else {
+            // not relevant to any of our streams, ignore
+            return;
+        };
 
-        let Some(rtt_usec) = stream_3_usec.checked_sub(stream_1_usec) else {
+        let Some(rtt_usec) = packet.data().round_trip_usec() else {
             // invalid packet, ignore
             return;
         };
 
+        let reply_age = time::now().0.saturating_sub(packet.data().receive_2.0);
+        if Duration::from_micros(reply_age) > MAX_PACKET_AGE {
+            // answers a time packet we replied to long ago, this can only
+            // be a replay. without fresh time exchanges a replayed stream
+            // never gets a clock estimate, and so is never played
+            return;
+        }
+
         let network_latency = Duration::from_micros(rtt_usec / 2);
         stream.latency.observe(network_latency);
 
         if let Some(latency) = stream.network_latency() {
-            self.stats.set_network_latency(latency);
+            stream.stats.set_network_latency(latency);
+        }
+
+        let accept = stream.clock_filter.observe(Duration::from_micros(rtt_usec));
+
+        stream.diagnostics.set_clock_samples(
+            stream.clock_filter.accepted(),
+            stream.clock_filter.rejected());
+
+        if let Some(spread) = stream.clock_filter.spread() {
+            stream.diagnostics.set_rtt_spread(spread);
+        }
+
+        if !accept {
+            // delta from this exchange is less trustworthy than others
+            // we've seen recently, don't use it
+            return;
         }
 
         let clock_delta = ClockDelta::from_time_packet(&packet);
-        stream.clock_delta.observe(clock_delta);
+        stream.clock.observe(packet.data().receive_2, clock_delta);
+
+        if let Some(ppm) = stream.clock.drift_ppm() {
+            stream.diagnostics.set_clock_drift(ppm);
+        }
     }
 
-    fn prepare_stream(&mut self, packet: &Audio) -> bool {
-        if let Some(stream) = self.stream.as_mut() {
+    fn prepare_stream(&mut self, slot: usize, packet: &Audio) -> bool {
+        let max_seq_gap = self.opt.max_seq_gap;
+
+        let is_current = self.slots[slot].stream.as_ref()
+            .is_some_and(|stream| stream.sid == packet.header().sid);
+
+        if !is_current && self.slots[slot].ended_sid == Some(packet.header().sid) {
+            // straggler from a stream which has already ended
+            return false;
+        }
+
+        if let Some(stream) = self.slots[slot].stream.as_ref() {
             let header = packet.header();
 
-            if header.sid < stream.sid {
+            if self.is_previous_session(slot, header.sid) {
                 // packet belongs to a previous stream, ignore
                 return false;
             }
 
-            if header.sid > stream.sid {
+            if header.sid != stream.sid || header.format != stream.format {
                 // new stream is taking over! switch over to it
                 println!("\nnew stream beginning");
-                self.stream = Some(Stream::start_from_packet(packet));
-                self.stats.clear();
-                self.queue.clear();
+                self.start_stream(slot, packet);
                 return true;
             }
 
@@ -156,220 +671,453 @@ impl Receiver {
                 return false;
             }
 
-            if let Some(front) = self.queue.front() {
+            if let Some(front) = stream.queue.front() {
                 if header.seq <= front.seq {
                     println!("\nreceived packet with seq <= queue front seq, dropping");
+                    // too late to be played, count it against the stream
+                    let stream = self.slots[slot].stream.as_mut().unwrap();
+                    stream.observe_arrival(true);
                     return false;
                 }
             }
 
-            if let Some(back) = self.queue.back() {
-                if back.seq + self.opt.max_seq_gap as u64 <= header.seq {
+            if let Some(back) = stream.queue.back() {
+                if back.seq + max_seq_gap as u64 <= header.seq {
                     println!("\nreceived packet with seq too far in future, resetting stream");
-                    self.stream = Some(Stream::start_from_packet(packet));
-                    self.stats.clear();
-                    self.queue.clear();
+                    self.start_stream(slot, packet);
                 }
             }
 
             true
         } else {
-            self.stream = Some(Stream::start_from_packet(packet));
-            self.stats.clear();
+            self.start_stream(slot, packet);
             true
         }
     }
 
-    pub fn receive_audio(&mut self, packet: Audio) {
-        let now = time::now();
+    fn start_stream(&mut self, slot: usize, packet: &Audio) {
+        let stream = Stream::start_from_packet(packet, self.output, &self.opt);
+        let slot = &mut self.slots[slot];
+        slot.previous = slot.stream.replace(stream);
+        slot.fade = Fade::start(self.output);
+    }
+
+    pub fn receive_audio(&mut self, packet: Audio, received: TimestampMicros) {
+        if !self.is_our_stream(packet.header()) {
+            return;
+        }
+
+        self.fec.observe(packet.header(), packet.as_packet());
+        self.queue_audio(packet, received);
+    }
+
+    fn queue_audio(&mut self, packet: Audio, received: TimestampMicros) {
+        let now = received;
+
+        // the queue only holds f32 audio, convert integer formats up front
+        let packet = codec::decode_pcm(packet);
+
+        let Some(slot) = self.slot_for(packet.header().stream) else {
+            return;
+        };
 
-        if !self.prepare_stream(&packet) {
+        if !self.prepare_stream(slot, &packet) {
             return;
         }
 
         // we are guaranteed that if prepare_stream returns true,
-        // self.stream is Some:
-        let stream = self.stream.as_ref().unwrap();
+        // the slot's stream is Some:
+        let stream = self.slots[slot].stream.as_mut().unwrap();
+
+        if self.authenticated && stream.packet_age(packet.header().dts, now).is_some_and(|age| age > MAX_PACKET_AGE) {
+            // sent long before now by the source's clock, a replay of
+            // recorded packets rather than late audio
+            return;
+        }
+
+        stream.last_packet = Instant::now();
+        stream.priority = packet.header().priority;
+        stream.observe_arrival(false);
+
+        // the queue holds audio in our output channel layout
+        let packet = stream.channel_mix.apply(packet);
 
         if let Some(latency) = stream.network_latency() {
-            if let Some(clock_delta) = stream.clock_delta.median() {
+            if let Some(clock_delta) = stream.clock.delta_at(now) {
                 let latency_usec = u64::try_from(latency.as_micros()).unwrap();
                 let delta_usec = clock_delta.as_micros();
                 let predict_dts = (now.0 - latency_usec).checked_add_signed(-delta_usec).unwrap();
                 let predict_diff = predict_dts as i64 - packet.header().dts.0 as i64;
-                self.stats.set_predict_offset(predict_diff)
+                stream.stats.set_predict_offset(predict_diff)
             }
         }
 
+        if let Some(clock_delta) = stream.clock.delta_at(now) {
+            // time from the source sending the packet to us receiving it,
+            // on the source's clock
+            let receive_usec = now.0 as i64 - clock_delta.as_micros();
+            stream.jitter.observe_transit(receive_usec - packet.header().dts.0 as i64);
+        }
+
         // INVARIANT: at this point we are guaranteed that, if there are
         // packets in the queue, the seq of the incoming packet is less than
         // back.seq + max_seq_gap
 
         // expand queue to make space for new packet
-        if let Some(back) = self.queue.back() {
+        if let Some(back) = stream.queue.back() {
             if packet.header().seq > back.seq {
+                // any slots between the back of the queue and the new
+                // packet are missing, ask for them to be sent again. only
+                // the most recent are worth asking for if the gap is large
+                let missing = packet.header().seq - back.seq - 1;
+                if missing > 0 {
+                    let count = std::cmp::min(missing, u64::from(Nack::MAX_COUNT));
+                    let seq = packet.header().seq - count;
+
+                    self.nack = Some(Nack::new(stream.sid, seq, count as u32)
+                        .expect("allocate Nack packet"));
+                }
+
                 // extend queue from back to make space for new packet
                 // this also allows for out of order packets
                 for seq in (back.seq + 1)..=packet.header().seq {
-                    self.queue.push_back(QueueEntry {
+                    stream.queue.push_back(QueueEntry {
                         seq,
                         pts: None,
                         consumed: SampleDuration::zero(),
                         packet: None,
+                        concealed: false,
                     })
                 }
             }
         } else {
             // queue is empty, insert missing packet slot for the packet we are about to receive
-            self.queue.push_back(QueueEntry {
+            stream.queue.push_back(QueueEntry {
                 seq: packet.header().seq,
                 pts: None,
                 consumed: SampleDuration::zero(),
                 packet: None,
+                concealed: false,
             });
         }
 
         // INVARIANT: at this point queue is non-empty and contains an
         // allocated slot for the packet we just received
-        let front_seq = self.queue.front().unwrap().seq;
+        let front_seq = stream.queue.front().unwrap().seq;
         let idx_for_packet = (packet.header().seq - front_seq) as usize;
 
-        let slot = self.queue.get_mut(idx_for_packet).unwrap();
-        assert!(slot.seq == packet.header().seq);
-        slot.pts = stream.adjust_pts(Timestamp::from_micros_lossy(packet.header().pts));
-        slot.packet = Some(packet);
+        let pts = stream.adjust_pts(Timestamp::from_micros_lossy(packet.header().pts, stream.format.sample_rate), now);
+
+        let entry = stream.queue.get_mut(idx_for_packet).unwrap();
+        assert!(entry.seq == packet.header().seq);
+
+        if entry.concealed {
+            if entry.consumed != SampleDuration::zero() {
+                // retransmission or fec recovery arrived after we started
+                // playing concealment in its place, too late to switch
+                return;
+            }
+
+            // concealment hasn't been played yet, use the real audio
+            entry.concealed = false;
+
+            if let Some(concealed) = entry.packet.take() {
+                stream.concealer.recycle(concealed);
+            }
+        }
+
+        entry.pts = pts;
+        entry.packet = Some(packet);
     }
 
-    pub fn fill_stream_buffer(&mut self, mut data: &mut [f32], pts: Timestamp) {
-        // complete frames only:
-        assert!(data.len() % 2 == 0);
+    pub fn receive_opus(&mut self, packet: AudioOpus, received: TimestampMicros) {
+        if !self.is_our_stream(packet.header()) {
+            return;
+        }
+
+        self.fec.observe(packet.header(), packet.as_packet());
+        self.decode_opus(packet, received);
+    }
+
+    fn decode_opus(&mut self, packet: AudioOpus, received: TimestampMicros) {
+        let sid = packet.header().sid;
 
-        // get stream start timing information:
-        let Some(stream) = self.stream.as_mut() else {
-            // stream hasn't started, just fill buffer with silence and return
-            data.fill(0f32);
+        let Some(slot) = self.slot_for(packet.header().stream) else {
             return;
         };
 
-        let real_ts_after_fill = pts.add(SampleDuration::from_buffer_offset(data.len()));
+        if self.is_previous_session(slot, sid) {
+            // packet belongs to a previous stream, ignore
+            return;
+        }
 
-        // sync up to stream if necessary:
-        if !stream.sync {
-            loop {
-                let Some(front) = self.queue.front_mut() else {
-                    // nothing at front of queue?
-                    data.fill(0f32);
+        // opus decoders are stateful, start a fresh one for each stream
+        let decoder = match self.slots[slot].opus.take() {
+            Some(decoder) if decoder.matches(&packet) => decoder,
+            _ => match OpusDecoder::new(sid, packet.header().format) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    eprintln!("\nerror creating opus decoder: {e:?}");
                     return;
-                };
+                }
+            }
+        };
 
-                let Some(front_pts) = front.pts else {
-                    // haven't received enough info to adjust pts of queue
-                    // front yet, just pop and ignore it
-                    self.queue.pop_front();
-                    // and output silence for this part:
-                    data.fill(0f32);
-                    return;
-                };
+        let decoder = self.slots[slot].opus.insert(decoder);
 
-                if pts > front_pts {
-                    // frame has already begun, we are late
-                    let late = pts.duration_since(front_pts);
+        match decoder.decode(&packet) {
+            Ok(audio) => self.queue_audio(audio, received),
+            Err(e) => eprintln!("\nerror decoding opus packet: {e:?}"),
+        }
+    }
 
-                    if late >= SampleDuration::ONE_PACKET {
-                        // we are late by more than a packet, skip to the next
-                        self.queue.pop_front();
-                        continue;
-                    }
+    pub fn receive_encrypted(&mut self, packet: AudioEncrypted, received: TimestampMicros) {
+        if !self.is_our_stream(packet.header()) {
+            return;
+        }
 
-                    // partially consume this packet to sync up
-                    front.consumed = late;
+        self.fec.observe(packet.header(), packet.as_packet());
 
-                    // we are synced
-                    stream.sync = true;
-                    self.stats.set_stream(StreamStatus::Sync);
-                    break;
+        let Some(key) = self.cipher.as_ref() else {
+            // no key to decrypt with, nothing we can do
+            return;
+        };
+
+        match packet.decrypt(key) {
+            Some(PacketKind::Audio(audio)) => self.queue_audio(audio, received),
+            Some(PacketKind::AudioOpus(opus)) => self.decode_opus(opus, received),
+            _ => {
+                let sid = packet.header().sid;
+                if self.warned_decrypt != Some(sid) {
+                    eprintln!("\nfailed to decrypt audio packet, is the stream using another key?");
+                    self.warned_decrypt = Some(sid);
                 }
+            }
+        }
+    }
 
-                // otherwise we are early
-                let early = front_pts.duration_since(pts);
+    pub fn receive_fec(&mut self, packet: AudioFec, received: TimestampMicros) {
+        let sid = packet.header().sid;
 
-                if early >= SampleDuration::from_buffer_offset(data.len()) {
-                    // we are early by more than what was asked of us in this
-                    // call, fill with zeroes and return
-                    data.fill(0f32);
-                    return;
-                }
+        // only recover packets for streams we're playing, or which could
+        // start playing in an empty slot
+        let wanted = self.slots.iter().any(|slot| match &slot.stream {
+            Some(stream) => stream.sid == sid,
+            None => true,
+        });
 
-                // we are early, but not an entire packet timing's early
-                // partially output some zeroes
-                let zero_count = early.as_buffer_offset();
-                data[0..zero_count].fill(0f32);
-                data = &mut data[zero_count..];
+        if !wanted {
+            return;
+        }
 
-                // then mark ourselves as synced and fall through to regular processing
-                stream.sync = true;
-                self.stats.set_stream(StreamStatus::Sync);
-                break;
+        match self.fec.recover(&packet) {
+            Some(PacketKind::Audio(audio)) => self.receive_audio(audio, received),
+            Some(PacketKind::AudioOpus(opus)) => self.receive_opus(opus, received),
+            Some(PacketKind::AudioEncrypted(encrypted)) => self.receive_encrypted(encrypted, received),
+            _ => {}
+        }
+    }
+
+    pub fn receive_goodbye(&mut self, packet: Goodbye) {
+        let sid = packet.data().sid;
+
+        let Some(slot) = self.slots.iter_mut()
+            .find(|slot| slot.stream.as_ref().is_some_and(|stream| stream.sid == sid))
+        else {
+            // not one of our streams, ignore
+            return;
+        };
+
+        slot.ended_sid = Some(sid);
+
+        let stream = slot.stream.as_mut().unwrap();
+        if !stream.ended {
+            // goodbyes are sent a few times over
+            println!("\nstream ended");
+            stream.ended = true;
+        }
+    }
+
+    pub fn receive_metadata(&mut self, packet: Metadata) {
+        let Some(stream) = self.session_stream_mut(packet.data().sid) else {
+            // metadata for a stream we aren't playing, ignore
+            return;
+        };
+
+        let metadata = StreamMetadata::from_packet(packet.data());
+
+        if stream.metadata.as_ref() != Some(&metadata) {
+            if let Some(now_playing) = metadata.now_playing() {
+                println!("\nnow playing: {now_playing}");
             }
         }
 
-        let mut stream_ts = None;
+        stream.metadata = Some(metadata);
+    }
 
-        // copy data to out
-        while data.len() > 0 {
-            let Some(front) = self.queue.front_mut() else {
-                data.fill(0f32);
-                self.stats.set_stream(StreamStatus::Miss);
+    pub fn receive_control(&mut self, packet: Control) {
+        let control = packet.data();
+
+        if control.flags.contains(ControlFlags::SET_VOLUME) {
+            if !control.volume.is_finite() {
+                // invalid packet, ignore
                 return;
-            };
+            }
 
-            let buffer = front.as_full_buffer();
-            let buffer_offset = front.consumed.as_buffer_offset();
-            let buffer_remaining = buffer.len() - buffer_offset;
+            self.volume = control.volume.clamp(0.0, 1.0);
+            println!("\nvolume set to {:.0}%", self.volume * 100.0);
+        }
 
-            let copy_count = std::cmp::min(data.len(), buffer_remaining);
-            let buffer_copy_end = buffer_offset + copy_count;
+        if control.flags.contains(ControlFlags::MUTE) {
+            self.muted = true;
+            println!("\nmuted");
+        } else if control.flags.contains(ControlFlags::UNMUTE) {
+            self.muted = false;
+            println!("\nunmuted");
+        }
+    }
 
-            let input = &buffer[buffer_offset..buffer_copy_end];
-            let output = &mut data[0..copy_count];
-            let result = stream.resampler.process_interleaved(input, output)
-                .expect("resample error!");
+    pub fn fill_stream_buffer(&mut self, data: &mut [f32], pts: TimestampMicros) {
+        // fill every stream, not just the one we're playing, so that any
+        // interrupted stream stays in sync and can resume where it should
+        for slot in &mut self.slots {
+            let Some(stream) = slot.stream.as_mut() else {
+                continue;
+            };
 
-            data = &mut data[result.output_written.as_buffer_offset()..];
-            front.consumed = front.consumed.add(result.input_read);
+            stream.fill(data.len(), pts, self.output);
 
-            stream_ts = front.pts.map(|front_pts| front_pts.add(front.consumed));
+            if let Some(previous) = slot.previous.as_mut() {
+                // the stream this one took over from is still playing out
+                // its queue, fade it out underneath the new stream
+                previous.fill(data.len(), pts, self.output);
+                slot.fade.apply(&mut stream.buffer, Some(&previous.buffer), self.output.channels);
 
-            // pop packet if fully consumed
-            if front.consumed == SampleDuration::ONE_PACKET {
-                self.queue.pop_front();
+                if slot.fade.is_finished() {
+                    slot.previous = None;
+                }
             }
         }
 
-        if let Some(stream_ts) = stream_ts {
-            let rate = stream.rate_adjust.sample_rate(Timing {
-                real: real_ts_after_fill,
-                play: stream_ts,
-            });
+        let selected = self.select_slot();
 
-            let _ = stream.resampler.set_input_rate(rate.0);
+        let buffer = |slot: Option<usize>| {
+            let stream = self.slots.get(slot?)?.stream.as_ref()?;
+            Some(stream.buffer.as_slice())
+        };
 
-            if stream.rate_adjust.slew() {
-                self.stats.set_stream(StreamStatus::Slew);
-            } else {
-                self.stats.set_stream(StreamStatus::Sync);
+        match buffer(selected) {
+            Some(buffer) => data.copy_from_slice(buffer),
+            None => data.fill(0f32),
+        }
+
+        if selected != self.playing {
+            // fade across from what we were playing rather than cutting
+            // over abruptly
+            self.fading_from = self.playing;
+            self.fade = Fade::start(self.output);
+        }
+
+        if !self.fade.is_finished() {
+            // the slot we're fading from has been filled again above, so
+            // the fade carries on where the last fill left off
+            self.fade.apply(data, buffer(self.fading_from), self.output.channels);
+        }
+
+        self.playing = selected;
+
+        let gain = if self.muted { 0.0 } else { self.volume };
+        apply_gain(data, self.gain, gain, self.output.channels);
+        self.gain = gain;
+
+        if let Some(stream) = self.playing_stream() {
+            (self.stats, self.diagnostics) = (stream.stats, stream.diagnostics);
+        }
+
+        for slot in 0..self.slots.len() {
+            let Some(stream) = self.slots[slot].stream.as_ref() else {
+                continue;
+            };
+
+            if stream.is_finished() {
+                // forget about the stream entirely now it has played out,
+                // the end of the queue was faded out as it ran out
+                self.reset_stream(slot);
+            } else if stream.is_expired(self.session_timeout()) {
+                // source went away without saying goodbye, we've been missing
+                // audio for a while now so there's nothing to fade
+                println!("\nstream timed out");
+                self.reset_stream(slot);
             }
+        }
+    }
+}
+
+/// Applies gain to `data`, ramping linearly from the previous gain
+fn apply_gain(data: &mut [f32], from: f32, to: f32, channels: ChannelCount) {
+    if from == 1.0 && to == 1.0 {
+        return;
+    }
+
+    let channels = usize::from(channels);
+    let count = (data.len() / channels) as f32;
+
+    for (index, sample) in data.iter_mut().enumerate() {
+        let ramp = ((index / channels) + 1) as f32 / count;
+        *sample *= from + (to - from) * ramp;
+    }
+}
+
+/// Number of output frames fades are applied over
+fn fade_frames(output: StreamFormat) -> usize {
+    SampleDuration::from_std_duration_lossy(FADE_DURATION, output.sample_rate)
+        .as_buffer_offset(ChannelCount(1))
+}
+
+/// Linear fade into new audio from other audio over `FADE_DURATION`, which
+/// may span several fills
+struct Fade {
+    // frames faded so far, and in total
+    position: usize,
+    length: usize,
+}
+
+impl Fade {
+    pub fn start(output: StreamFormat) -> Self {
+        Fade { position: 0, length: fade_frames(output) }
+    }
+
+    pub fn finished() -> Self {
+        Fade { position: 0, length: 0 }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.length
+    }
 
-            self.stats.set_audio_latency(real_ts_after_fill, stream_ts);
+    /// Fades into `data` from another buffer of the same length, carrying
+    /// on from where the last call left off. A missing buffer is treated
+    /// as silence
+    pub fn apply(&mut self, data: &mut [f32], from: Option<&[f32]>, channels: ChannelCount) {
+        if self.is_finished() {
+            return;
         }
 
-        self.stats.set_buffer_length(self.queue.iter()
-            .map(|entry| SampleDuration::ONE_PACKET.sub(entry.consumed))
-            .fold(SampleDuration::zero(), |cum, dur| cum.add(dur)));
+        let channels = usize::from(channels);
+
+        for (index, sample) in data.iter_mut().enumerate() {
+            let position = self.position + index / channels + 1;
+            let gain = f32::min(position as f32 / self.length as f32, 1.0);
+            let from = from.map(|buffer| buffer[index]).unwrap_or(0f32);
+            *sample = from * (1.0 - gain) + *sample * gain;
+        }
+
+        self.position += data.len() / channels;
     }
 }
 
 struct RateAdjust {
+    base_rate: SampleRate,
     slew: bool,
 }
 
@@ -380,8 +1128,9 @@ pub struct Timing {
 }
 
 impl RateAdjust {
-    pub fn new() -> Self {
+    pub fn new(base_rate: SampleRate) -> Self {
         RateAdjust {
+            base_rate,
             slew: false
         }
     }
@@ -391,7 +1140,7 @@ impl RateAdjust {
     }
 
     pub fn sample_rate(&mut self, timing: Timing) -> SampleRate {
-        self.adjusted_rate(timing).unwrap_or(bark_protocol::SAMPLE_RATE)
+        self.adjusted_rate(timing).unwrap_or(self.base_rate)
     }
 
     fn adjusted_rate(&mut self, timing: Timing) -> Option<SampleRate> {
@@ -401,8 +1150,8 @@ impl RateAdjust {
         let slew_target_duration = Duration::from_millis(500);
 
         // turn them into native units
-        let start_slew_threshold = SampleDuration::from_std_duration_lossy(start_slew_threshold);
-        let stop_slew_threshold = SampleDuration::from_std_duration_lossy(stop_slew_threshold);
+        let start_slew_threshold = SampleDuration::from_std_duration_lossy(start_slew_threshold, self.base_rate);
+        let stop_slew_threshold = SampleDuration::from_std_duration_lossy(stop_slew_threshold, self.base_rate);
 
         let frame_offset = timing.real.delta(timing.play);
 
@@ -416,7 +1165,7 @@ impl RateAdjust {
         }
 
         let slew_duration_duration = i64::try_from(slew_target_duration.as_micros()).unwrap();
-        let base_sample_rate = i64::from(bark_protocol::SAMPLE_RATE);
+        let base_sample_rate = i64::from(self.base_rate);
         let rate_offset = frame_offset.as_frames() * 1_000_000 / slew_duration_duration;
         let rate = base_sample_rate + rate_offset;
 
@@ -471,10 +1220,62 @@ pub struct ReceiveOpt {
     pub device: Option<String>,
     #[structopt(long, default_value="12")]
     pub max_seq_gap: usize,
+    /// Stream channels to play, either auto to play everything, or a comma
+    /// separated list of channel names (FL, FR, FC, LFE, SL, SR, BL, BR) or
+    /// indices to play on each output channel
+    #[structopt(long, env = "BARK_RECEIVE_CHANNELS", default_value = "auto")]
+    pub channels: ChannelMap,
+    /// Time without audio after which a stream is considered dead, and a
+    /// new stream from any source is accepted
+    #[structopt(long, env = "BARK_RECEIVE_SESSION_TIMEOUT_MS", default_value = "1000")]
+    pub session_timeout_ms: u64,
+    /// Name of the stream to play, when several streams share a multicast
+    /// group. By default the most recently started stream is played
+    #[structopt(long, env = "BARK_RECEIVE_STREAM")]
+    pub stream: Option<String>,
+    /// Names of announcement streams, comma separated. An announcement
+    /// interrupts the stream being played, which resumes once the
+    /// announcement ends
+    #[structopt(long, env = "BARK_RECEIVE_ANNOUNCE", use_delimiter = true)]
+    pub announce: Vec<String>,
+    /// Name of this receiver, used to address it with bark control. By
+    /// default receivers can only be controlled all at once
+    #[structopt(long, env = "BARK_RECEIVE_NAME")]
+    pub name: Option<String>,
+    /// Fixed offset to apply to playback in milliseconds, to compensate for
+    /// the distance between speakers and listeners. Positive values delay
+    /// playback
+    #[structopt(long, env = "BARK_RECEIVE_OFFSET_MS", default_value = "0", allow_hyphen_values = true)]
+    pub offset_ms: i64,
+    /// Measure network jitter and report the lowest latency this receiver
+    /// can play at without packets arriving late, for stream sources to
+    /// adapt their delay to
+    #[structopt(long, env = "BARK_RECEIVE_ADAPTIVE_LATENCY", default_value = "false", parse(try_from_str))]
+    pub adaptive_latency: bool,
+    /// Lowest latency reported in adaptive latency mode
+    #[structopt(long, env = "BARK_RECEIVE_MIN_LATENCY_MS", default_value = "5")]
+    pub min_latency_ms: u64,
+    /// Highest latency reported in adaptive latency mode
+    #[structopt(long, env = "BARK_RECEIVE_MAX_LATENCY_MS", default_value = "200")]
+    pub max_latency_ms: u64,
+}
+
+impl ReceiveOpt {
+    /// Stream we're playing, or `None` to play any stream
+    pub fn stream_filter(&self) -> Option<StreamId> {
+        self.stream.as_deref().map(StreamId::from_name)
+    }
+
+    pub fn announce_streams(&self) -> Vec<StreamId> {
+        self.announce.iter().map(|name| StreamId::from_name(name)).collect()
+    }
 }
 
 pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
-    let receiver_id = generate_receiver_id();
+    let receiver_id = match &opt.name {
+        Some(name) => ReceiverId::from_name(name),
+        None => generate_receiver_id(),
+    };
     let node = stats::node::get();
 
     if let Some(device) = &opt.device {
@@ -486,16 +1287,21 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
     let device = host.default_output_device()
         .ok_or(RunError::NoDeviceAvailable)?;
 
-    let config = util::config_for_device(&device)?;
+    // we always output in the default format, streams in other formats
+    // are resampled and converted to it
+    let output = StreamFormat::DEFAULT;
+    let config = util::config_for_device(&device, output)?;
 
     struct SharedState {
         pub recv: Receiver,
     }
 
     let state = Arc::new(Mutex::new(SharedState {
-        recv: Receiver::new(opt.clone()),
+        recv: Receiver::new(opt.clone(), output),
     }));
 
+    let offset_usec = opt.offset_ms.saturating_mul(1000);
+
     let _stream = device.build_output_stream(&config,
         {
             let state = state.clone();
@@ -513,12 +1319,16 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
                     .duration_since(&stream_timestamp.callback)
                     .unwrap_or_default();
 
-                let output_latency = SampleDuration::from_std_duration_lossy(output_latency);
+                let output_latency_usec = u64::try_from(output_latency.as_micros()).unwrap();
 
-                let now = Timestamp::from_micros_lossy(time::now());
-                let pts = now.add(output_latency);
+                let pts = TimestampMicros(time::now().0 + output_latency_usec);
+
+                // delaying playback by the offset means playing audio from
+                // earlier in the stream
+                let pts = TimestampMicros(pts.0.saturating_add_signed(-offset_usec));
 
                 let mut state = state.lock().unwrap();
+                state.recv.set_output_latency(output_latency);
                 state.recv.fill_stream_buffer(data, pts);
             }
         },
@@ -528,18 +1338,37 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
         None
     ).map_err(RunError::BuildStream)?;
 
+    // stream we're playing, if set, and any announcement streams
+    let stream_filter = opt.stream_filter();
+    let announce_streams = opt.announce_streams();
+
+    let auth = opt.socket.auth_key();
+
     let socket = Socket::open(opt.socket)
         .map_err(RunError::Listen)?;
 
-    let protocol = ProtocolSocket::new(socket);
+    let protocol = ProtocolSocket::new(socket, auth);
+
+    // control packets carry no session, so recent ones are remembered to
+    // reject replays. only possible when packets are authenticated
+    let mut control_replay = protocol.auth().is_some().then(ControlReplay::new);
 
     crate::thread::set_name("bark/network");
     crate::thread::set_realtime_priority();
 
+    // last stream we warned about a newer protocol version for
+    let mut warned_sid = None;
+
+    let request_retransmit = |nack: Option<Nack>, peer| {
+        if let Some(nack) = nack {
+            let _ = protocol.send_to(nack.as_packet(), peer);
+        }
+    };
+
     loop {
-        let (packet, peer) = protocol.recv_from().map_err(RunError::Socket)?;
+        let (packet, peer, received) = protocol.recv_from().map_err(RunError::Socket)?;
 
-        match packet.parse() {
+        match packet.parse(protocol.auth()) {
             Some(PacketKind::Time(mut time)) => {
                 if !time.data().rid.matches(&receiver_id) {
                     // not for us - time packets are usually unicast,
@@ -547,11 +1376,29 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
                     continue;
                 }
 
+                let stream = time.data().stream;
+                if stream_filter.is_some() && stream_filter != Some(stream) && !announce_streams.contains(&stream) {
+                    // time packet for a stream we're not playing
+                    continue;
+                }
+
                 match time.data().phase() {
                     Some(TimePhase::Broadcast) => {
                         let data = time.data_mut();
-                        data.receive_2 = time::now();
+                        data.receive_2 = received;
                         data.rid = receiver_id;
+                        data.receive_protocol = ProtocolInfo::current();
+
+                        let stream = data.stream_protocol;
+                        if stream.version > bark_protocol::PROTOCOL_VERSION && warned_sid != Some(data.sid) {
+                            eprintln!("\nwarning: stream {} uses protocol version {}, newer than our version {}",
+                                peer, stream.version, bark_protocol::PROTOCOL_VERSION);
+                            warned_sid = Some(data.sid);
+                        }
+
+                        // stamp as late as possible, so that the time we
+                        // took to reply isn't counted as network delay
+                        time.data_mut().receive_reply = time::now();
 
                         protocol.send_to(time.as_packet(), peer)
                             .expect("reply to time packet");
@@ -568,15 +1415,72 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
             }
             Some(PacketKind::Audio(packet)) => {
                 let mut state = state.lock().unwrap();
-                state.recv.receive_audio(packet);
+                state.recv.receive_audio(packet, received);
+                let nack = state.recv.take_nack();
+                drop(state);
+
+                request_retransmit(nack, peer);
+            }
+            Some(PacketKind::AudioOpus(packet)) => {
+                let mut state = state.lock().unwrap();
+                state.recv.receive_opus(packet, received);
+                let nack = state.recv.take_nack();
+                drop(state);
+
+                request_retransmit(nack, peer);
+            }
+            Some(PacketKind::AudioEncrypted(packet)) => {
+                let mut state = state.lock().unwrap();
+                state.recv.receive_encrypted(packet, received);
+                let nack = state.recv.take_nack();
+                drop(state);
+
+                request_retransmit(nack, peer);
+            }
+            Some(PacketKind::AudioFec(packet)) => {
+                let mut state = state.lock().unwrap();
+                state.recv.receive_fec(packet, received);
+                let nack = state.recv.take_nack();
+                drop(state);
+
+                request_retransmit(nack, peer);
+            }
+            Some(PacketKind::Goodbye(packet)) => {
+                let mut state = state.lock().unwrap();
+                state.recv.receive_goodbye(packet);
+            }
+            Some(PacketKind::Metadata(packet)) => {
+                let mut state = state.lock().unwrap();
+                state.recv.receive_metadata(packet);
+            }
+            Some(PacketKind::Control(packet)) => {
+                if !packet.data().rid.matches(&receiver_id) {
+                    // addressed to another receiver
+                    continue;
+                }
+
+                if let Some(replay) = control_replay.as_mut() {
+                    if !replay.accept(packet.data().sent) {
+                        continue;
+                    }
+                }
+
+                let mut state = state.lock().unwrap();
+                state.recv.receive_control(packet);
+            }
+            Some(PacketKind::Nack(_)) => {
+                // only of interest to stream sources
             }
             Some(PacketKind::StatsRequest(_)) => {
                 let state = state.lock().unwrap();
                 let sid = state.recv.current_session().unwrap_or(SessionId::zeroed());
-                let receiver = *state.recv.stats();
+                let receiver = state.recv.stats();
+                let mut diagnostics = state.recv.diagnostics();
                 drop(state);
 
-                let reply = StatsReply::receiver(sid, receiver, node)
+                diagnostics.set_kernel_timestamps(protocol.kernel_timestamps());
+
+                let reply = StatsReply::receiver(sid, receiver, diagnostics, node)
                     .expect("allocate StatsReply packet");
 
                 let _ = protocol.send_to(reply.as_packet(), peer);
@@ -591,6 +1495,40 @@ pub fn run(opt: ReceiveOpt) -> Result<(), RunError> {
     }
 }
 
+/// Rejects replayed control packets. Packets must have been sent recently,
+/// and each one is only accepted once
+struct ControlReplay {
+    // sent timestamps of packets accepted within the window
+    accepted: VecDeque<TimestampMicros>,
+}
+
+impl ControlReplay {
+    // how far the sender's clock may be from ours, plus network delay
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        ControlReplay { accepted: VecDeque::new() }
+    }
+
+    pub fn accept(&mut self, sent: TimestampMicros) -> bool {
+        let now = time::realtime_now().0;
+        let window = Self::WINDOW.as_micros() as u64;
+
+        self.accepted.retain(|accepted| accepted.0 + window >= now);
+
+        if sent.0.abs_diff(now) > window {
+            return false;
+        }
+
+        if self.accepted.iter().any(|accepted| accepted.0 == sent.0) {
+            return false;
+        }
+
+        self.accepted.push_back(sent);
+        true
+    }
+}
+
 pub fn generate_receiver_id() -> ReceiverId {
     ReceiverId(rand::random())
 }
@@ -2,6 +2,7 @@ use std::ffi::{c_void, c_int, CStr};
 use std::fmt::Debug;
 use std::ptr;
 
+use bark_protocol::{ChannelCount, SampleRate};
 use bark_protocol::time::SampleDuration;
 
 use self::ffi::speex_resampler_strerror;
@@ -41,6 +42,8 @@ mod ffi {
 
 pub struct Resampler {
     ptr: ResamplerPtr,
+    channels: ChannelCount,
+    output_rate: SampleRate,
 }
 
 unsafe impl Send for Resampler {}
@@ -53,14 +56,14 @@ pub struct ProcessResult {
 }
 
 impl Resampler {
-    pub fn new() -> Self {
+    pub fn new(channels: ChannelCount, input_rate: SampleRate, output_rate: SampleRate) -> Self {
         let mut err: c_int = 0;
 
         let ptr = unsafe {
             ffi::speex_resampler_init(
-                bark_protocol::CHANNELS.into(),
-                bark_protocol::SAMPLE_RATE.into(),
-                bark_protocol::SAMPLE_RATE.into(),
+                channels.into(),
+                input_rate.into(),
+                output_rate.into(),
                 10,
                 &mut err
             )
@@ -74,7 +77,11 @@ impl Resampler {
             panic!("speex_resampler_init failed: {err:?}");
         }
 
-        Resampler { ptr: ResamplerPtr(ptr) }
+        Resampler {
+            ptr: ResamplerPtr(ptr),
+            channels,
+            output_rate,
+        }
     }
 
     pub fn set_input_rate(&mut self, rate: u32) -> Result<(), SpeexError> {
@@ -82,7 +89,7 @@ impl Resampler {
             ffi::speex_resampler_set_rate(
                 self.ptr.0,
                 rate,
-                bark_protocol::SAMPLE_RATE.into(),
+                self.output_rate.into(),
             )
         };
 
@@ -97,8 +104,8 @@ impl Resampler {
         -> Result<ProcessResult, SpeexError>
     {
         // speex API takes frame count:
-        let input_len = input.len() / usize::from(bark_protocol::CHANNELS);
-        let output_len = output.len() / usize::from(bark_protocol::CHANNELS);
+        let input_len = input.len() / usize::from(self.channels);
+        let output_len = output.len() / usize::from(self.channels);
 
         // usize could technically be 64 bit, speex only takes u32 sizes,
         // we don't want to panic or truncate, so let's just pick a reasonable
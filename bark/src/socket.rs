@@ -1,14 +1,23 @@
-use std::io;
+use std::borrow::Cow;
+use std::io::{self, IoSliceMut};
 use std::net::{Ipv4Addr, UdpSocket, SocketAddr, SocketAddrV4};
 use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use derive_more::Display;
 use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::{sockopt, ControlMessageOwned, MsgFlags, SockaddrIn};
+use nix::sys::time::TimeSpec;
 use socket2::{Domain, Type};
 use structopt::StructOpt;
 
+use bark_protocol::auth::{self, AuthKey};
 use bark_protocol::buffer::PacketBuffer;
+use bark_protocol::crypt::CipherKey;
 use bark_protocol::packet::Packet;
+use bark_protocol::types::TimestampMicros;
+
+use crate::time;
 
 // expedited forwarding - IP header field indicating that switches should
 // prioritise our packets for minimal delay
@@ -28,6 +37,22 @@ pub struct SocketOpt {
     #[structopt(long, name="addr", env = "BARK_MULTICAST")]
     /// Multicast group address including port, eg. 224.100.100.100:1530
     pub multicast: SocketAddrV4,
+
+    #[structopt(long, env = "BARK_KEY", hide_env_values = true)]
+    /// Pre-shared key to authenticate packets with. When set, packets
+    /// without a valid authentication code are ignored, so every node in
+    /// the group must be configured with the same key
+    pub key: Option<String>,
+}
+
+impl SocketOpt {
+    pub fn auth_key(&self) -> Option<AuthKey> {
+        self.key.as_ref().map(|key| AuthKey::new(key.as_bytes()))
+    }
+
+    pub fn cipher_key(&self) -> Option<CipherKey> {
+        self.key.as_ref().map(|key| CipherKey::derive(key.as_bytes()))
+    }
 }
 
 pub struct Socket {
@@ -39,6 +64,9 @@ pub struct Socket {
 
     // uses to receive multicast packets
     rx: UdpSocket,
+
+    // whether the last packet received was timestamped by the kernel
+    kernel_timestamps: AtomicBool,
 }
 
 #[derive(Clone, Copy, Debug, Display, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -56,6 +84,7 @@ impl Socket {
             multicast: SocketAddrV4::new(group, port),
             tx: tx.into(),
             rx: rx.into(),
+            kernel_timestamps: AtomicBool::new(false),
         })
     }
 
@@ -69,7 +98,9 @@ impl Socket {
         Ok(())
     }
 
-    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, PeerId), io::Error> {
+    /// Receives a packet, returning its length, sender, and the time it
+    /// was received
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, PeerId, TimestampMicros), io::Error> {
         let mut poll = [
             PollFd::new(self.tx.as_raw_fd(), PollFlags::POLLIN),
             PollFd::new(self.rx.as_raw_fd(), PollFlags::POLLIN),
@@ -77,19 +108,52 @@ impl Socket {
 
         nix::poll::poll(&mut poll, -1)?;
 
-        let (nbytes, addr) =
+        let socket =
             if poll[0].any() == Some(true) {
-                self.tx.recv_from(buf)?
+                &self.tx
             } else if poll[1].any() == Some(true) {
-                self.rx.recv_from(buf)?
+                &self.rx
             } else {
                 unreachable!("poll returned with no readable sockets");
             };
 
-        Ok((nbytes, PeerId(addr)))
+        let (nbytes, peer, kernel_timestamp) = recv_timestamped(socket, buf)?;
+
+        self.kernel_timestamps.store(kernel_timestamp.is_some(), Ordering::Relaxed);
+
+        Ok((nbytes, peer, kernel_timestamp.unwrap_or_else(time::now)))
+    }
+
+    /// Whether the last packet received was timestamped by the kernel as
+    /// it arrived, rather than when we read it
+    pub fn kernel_timestamps(&self) -> bool {
+        self.kernel_timestamps.load(Ordering::Relaxed)
     }
 }
 
+/// Receives a packet along with the time the kernel received it, so that
+/// any delay in waking us up isn't counted as network delay. The timestamp
+/// is `None` if the kernel didn't give us a usable one
+fn recv_timestamped(socket: &UdpSocket, buf: &mut [u8]) -> Result<(usize, PeerId, Option<TimestampMicros>), io::Error> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(TimeSpec);
+
+    let msg = nix::sys::socket::recvmsg::<SockaddrIn>(
+        socket.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+
+    let kernel_timestamp = msg.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmTimestampns(timestamp) => Some(timestamp),
+        _ => None,
+    });
+
+    let timestamp = kernel_timestamp.and_then(time::from_realtime);
+
+    let addr = msg.address
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "received packet without address"))?;
+
+    Ok((msg.bytes, PeerId(SocketAddr::V4(addr.into())), timestamp))
+}
+
 fn open_multicast(group: Ipv4Addr, bind: SocketAddrV4) -> Result<socket2::Socket, ListenError> {
     let socket = bind_socket(bind)?;
 
@@ -101,6 +165,11 @@ fn open_multicast(group: Ipv4Addr, bind: SocketAddrV4) -> Result<socket2::Socket
     socket.set_broadcast(true).map_err(ListenError::SetBroadcast)?;
     let _ = socket.set_multicast_loop_v4(true);
 
+    // have the kernel timestamp packets as they arrive
+    if let Err(e) = nix::sys::socket::setsockopt(socket.as_raw_fd(), sockopt::ReceiveTimestampns, &true) {
+        eprintln!("warning: failed to set SO_TIMESTAMPNS: {e:?}");
+    }
+
     Ok(socket.into())
 }
 
@@ -121,25 +190,51 @@ fn bind_socket(bind: SocketAddrV4) -> Result<socket2::Socket, ListenError> {
 
 pub struct ProtocolSocket {
     socket: Socket,
+    auth: Option<AuthKey>,
 }
 
 impl ProtocolSocket {
-    pub fn new(socket: Socket) -> Self {
-        ProtocolSocket { socket }
+    /// Whether received packets are being timestamped by the kernel
+    pub fn kernel_timestamps(&self) -> bool {
+        self.socket.kernel_timestamps()
+    }
+
+    pub fn new(socket: Socket, auth: Option<AuthKey>) -> Self {
+        ProtocolSocket { socket, auth }
+    }
+
+    /// Key received packets must be authenticated with, to be passed to
+    /// `Packet::parse`
+    pub fn auth(&self) -> Option<&AuthKey> {
+        self.auth.as_ref()
+    }
+
+    fn sign<'a>(&self, packet: &'a Packet) -> Cow<'a, [u8]> {
+        let frame = packet.frame();
+
+        match &self.auth {
+            Some(key) => {
+                let mut signed = Vec::with_capacity(frame.len() + auth::MAC_LENGTH);
+                signed.extend_from_slice(frame);
+                signed.extend_from_slice(&key.sign(frame));
+                Cow::Owned(signed)
+            }
+            None => Cow::Borrowed(frame),
+        }
     }
 
     pub fn broadcast(&self, packet: &Packet) -> Result<(), io::Error> {
-        self.socket.broadcast(packet.as_buffer().as_bytes())
+        self.socket.broadcast(&self.sign(packet))
     }
 
     pub fn send_to(&self, packet: &Packet, peer: PeerId) -> Result<(), io::Error> {
-        self.socket.send_to(packet.as_buffer().as_bytes(), peer)
+        self.socket.send_to(&self.sign(packet), peer)
     }
 
-    fn recv_buffer_from(&self) -> Result<(PacketBuffer, PeerId), io::Error> {
+    fn recv_buffer_from(&self) -> Result<(PacketBuffer, PeerId, TimestampMicros), io::Error> {
         let mut buffer = vec![0u8; bark_protocol::packet::MAX_PACKET_SIZE];
 
-        let (nbytes, peer) = self.socket.recv_from(&mut buffer)?;
+        let (nbytes, peer, timestamp) = self.socket.recv_from(&mut buffer)?;
 
         // shrink vec to what we just read:
         assert!(nbytes <= buffer.len());
@@ -147,15 +242,17 @@ impl ProtocolSocket {
 
         let buffer = PacketBuffer::from_raw(buffer);
 
-        Ok((buffer, peer))
+        Ok((buffer, peer, timestamp))
     }
 
-    pub fn recv_from(&self) -> Result<(Packet, PeerId), io::Error> {
+    /// Receives the next valid packet, along with its sender and the time
+    /// it was received
+    pub fn recv_from(&self) -> Result<(Packet, PeerId, TimestampMicros), io::Error> {
         loop {
-            let (buffer, peer) = self.recv_buffer_from()?;
+            let (buffer, peer, timestamp) = self.recv_buffer_from()?;
 
             if let Some(packet) = Packet::from_buffer(buffer) {
-                return Ok((packet, peer));
+                return Ok((packet, peer, timestamp));
             }
         }
     }
@@ -10,8 +10,9 @@ use structopt::StructOpt;
 use termcolor::BufferedStandardStream;
 
 use bark_protocol::packet::{StatsRequest, StatsReply, PacketKind};
-use bark_protocol::types::StatsReplyFlags;
+use bark_protocol::types::{SessionId, StatsReplyFlags};
 
+use crate::metadata::StreamMetadata;
 use crate::socket::{Socket, SocketOpt, PeerId, ProtocolSocket};
 use crate::RunError;
 
@@ -24,10 +25,12 @@ pub struct StatsOpt {
 }
 
 pub fn run(opt: StatsOpt) -> Result<(), RunError> {
+    let auth = opt.socket.auth_key();
+
     let socket = Socket::open(opt.socket)
         .map_err(RunError::Listen)?;
 
-    let protocol = Arc::new(ProtocolSocket::new(socket));
+    let protocol = Arc::new(ProtocolSocket::new(socket, auth));
 
     // spawn poller thread
     std::thread::spawn({
@@ -45,11 +48,20 @@ pub fn run(opt: StatsOpt) -> Result<(), RunError> {
 
     let mut stats = HashMap::<PeerId, Entry>::new();
 
-    loop {
-        let (reply, peer) = protocol.recv_from().map_err(RunError::Socket)?;
+    // latest metadata from each stream source
+    let mut metadata = HashMap::<PeerId, (SessionId, StreamMetadata)>::new();
 
-        let Some(PacketKind::StatsReply(reply)) = reply.parse() else {
-            continue;
+    loop {
+        let (reply, peer, _) = protocol.recv_from().map_err(RunError::Socket)?;
+
+        let reply = match reply.parse(protocol.auth()) {
+            Some(PacketKind::StatsReply(reply)) => reply,
+            Some(PacketKind::Metadata(packet)) => {
+                let data = packet.data();
+                metadata.insert(peer, (data.sid, StreamMetadata::from_packet(data)));
+                continue;
+            }
+            _ => { continue; }
         };
 
         let prev_entries = stats.len();
@@ -78,7 +90,11 @@ pub fn run(opt: StatsOpt) -> Result<(), RunError> {
         for (peer, entry) in &stats {
             // kill line
             kill_line(&mut out);
-            render::line(&mut out, &padding, &entry.reply, **peer);
+            let stream_metadata = metadata.get(*peer)
+                .filter(|(sid, _)| *sid == entry.reply.data().sid)
+                .map(|(_, metadata)| metadata);
+
+            render::line(&mut out, &padding, &entry.reply, stream_metadata, **peer);
             new_line(&mut out);
         }
 
@@ -1,10 +1,11 @@
 use termcolor::{WriteColor, ColorSpec, Color};
 
 use bark_protocol::packet::StatsReply;
-use bark_protocol::types::{StatsReplyPacket, StatsReplyFlags};
-use bark_protocol::types::stats::receiver::{ReceiverStats, StreamStatus};
+use bark_protocol::types::{StatsReplyPacket, StatsReplyFlags, ProtocolInfo};
+use bark_protocol::types::stats::receiver::{ReceiverDiagnostics, ReceiverStats, StreamStatus};
 use bark_protocol::types::stats::node::NodeStats;
 
+use crate::metadata::StreamMetadata;
 use crate::socket::PeerId;
 use super::node;
 
@@ -22,17 +23,39 @@ pub fn calculate(padding: &mut Padding, stats: &StatsReplyPacket, peer: PeerId)
     padding.peer_width = std::cmp::max(padding.peer_width, peer_width);
 }
 
-pub fn line(out: &mut dyn WriteColor, padding: &Padding, stats: &StatsReply, peer: PeerId) {
+pub fn line(out: &mut dyn WriteColor, padding: &Padding, stats: &StatsReply, metadata: Option<&StreamMetadata>, peer: PeerId) {
     node(out, padding, &stats.data().node, peer);
+    protocol(out, &stats.data().protocol);
 
     if stats.flags().contains(StatsReplyFlags::IS_RECEIVER) {
-        receiver(out, &stats.data().receiver);
+        receiver(out, &stats.data().receiver, &stats.data().diagnostics);
     } else if stats.flags().contains(StatsReplyFlags::IS_STREAM) {
         let _ = out.set_color(&ColorSpec::new()
             .set_fg(Some(Color::White))
             .set_bold(true));
         let _ = write!(out, "stream source");
         let _ = out.set_color(&ColorSpec::new());
+
+        if let Some(metadata) = metadata {
+            stream_metadata(out, metadata);
+        }
+    }
+}
+
+fn stream_metadata(out: &mut dyn WriteColor, metadata: &StreamMetadata) {
+    if !metadata.name.is_empty() {
+        let _ = out.set_color(&ColorSpec::new()
+            .set_fg(Some(Color::Cyan))
+            .set_bold(true));
+        let _ = write!(out, "  {}", metadata.name);
+        let _ = out.set_color(&ColorSpec::new());
+    }
+
+    if let Some(now_playing) = metadata.now_playing() {
+        let _ = out.set_color(&ColorSpec::new()
+            .set_dimmed(true));
+        let _ = write!(out, "  {}", now_playing);
+        let _ = out.set_color(&ColorSpec::new());
     }
 }
 
@@ -51,13 +74,69 @@ fn node(out: &mut dyn WriteColor, padding: &Padding, node: &NodeStats, peer: Pee
     let _ = out.set_color(&ColorSpec::new());
 }
 
-fn receiver(out: &mut dyn WriteColor, stats: &ReceiverStats) {
+fn protocol(out: &mut dyn WriteColor, protocol: &ProtocolInfo) {
+    let mut spec = ColorSpec::new();
+
+    if protocol.version == bark_protocol::PROTOCOL_VERSION {
+        spec.set_dimmed(true);
+    } else {
+        // highlight nodes speaking a different protocol version to us
+        spec.set_fg(Some(Color::Yellow));
+    }
+
+    let _ = out.set_color(&spec);
+    let _ = write!(out, "v{:<3} ", protocol.version);
+    let _ = out.set_color(&ColorSpec::new());
+}
+
+fn receiver(out: &mut dyn WriteColor, stats: &ReceiverStats, diagnostics: &ReceiverDiagnostics) {
     stream_status(out, stats.stream());
 
     time_field(out, "Audio", stats.audio_latency());
     time_field(out, "Buffer", stats.buffer_length());
     time_field(out, "Network", stats.network_latency());
     time_field(out, "Predict", stats.predict_offset());
+    count_field(out, "Concealed", diagnostics.concealed_packets());
+    percent_field(out, "Late", diagnostics.late_packet_rate());
+
+    if let Some((accepted, rejected)) = diagnostics.clock_samples() {
+        let _ = write!(out, "  Clock:[{:>5}/{:<5}]", accepted, rejected);
+    }
+
+    if let Some(spread) = diagnostics.rtt_spread() {
+        time_field(out, "RTT spread", Some(spread));
+    }
+
+    if let Some(kernel) = diagnostics.kernel_timestamps() {
+        let source = if kernel { "kernel" } else { "user" };
+        let _ = write!(out, "  Timestamps:[{:<6}]", source);
+    }
+
+    if let Some(ppm) = diagnostics.clock_drift() {
+        let _ = write!(out, "  Drift:[{:>+7.2} ppm]", ppm);
+    }
+
+    if let Some(latency) = diagnostics.requested_latency() {
+        time_field(out, "Want", Some(latency));
+    }
+
+    if stats.offset_ms() != 0 {
+        let _ = write!(out, "  Offset:[{:>+5} ms]", stats.offset_ms());
+    }
+
+    volume_field(out, stats);
+}
+
+fn volume_field(out: &mut dyn WriteColor, stats: &ReceiverStats) {
+    if stats.muted() {
+        let _ = out.set_color(&ColorSpec::new()
+            .set_fg(Some(Color::Red))
+            .set_bold(true));
+        let _ = write!(out, "  MUTED");
+        let _ = out.set_color(&ColorSpec::new());
+    } else if let Some(volume) = stats.volume() {
+        let _ = write!(out, "  Vol:[{:>3.0}%]", volume * 100.0);
+    }
 }
 
 fn stream_status(out: &mut dyn WriteColor, stream: Option<StreamStatus>) {
@@ -97,6 +176,10 @@ fn indicator_style(value: Option<StreamStatus>) -> (ColorSpec, &'static str) {
                 .set_bold(true)
                 .set_intense(true);
         }
+        Some(StreamStatus::Idle) => {
+            text = "IDLE";
+            spec.set_dimmed(true);
+        }
         None => {
             text = "    ";
         }
@@ -105,6 +188,22 @@ fn indicator_style(value: Option<StreamStatus>) -> (ColorSpec, &'static str) {
     (spec, text)
 }
 
+fn count_field(out: &mut dyn WriteColor, name: &str, value: Option<u64>) {
+    if let Some(count) = value {
+        let _ = write!(out, "  {name}:[{:>6}]", count);
+    } else {
+        let _ = write!(out, "  {name}:[      ]");
+    }
+}
+
+fn percent_field(out: &mut dyn WriteColor, name: &str, value: Option<f64>) {
+    if let Some(fraction) = value {
+        let _ = write!(out, "  {name}:[{:>5.1}%]", fraction * 100.0);
+    } else {
+        let _ = write!(out, "  {name}:[      ]");
+    }
+}
+
 fn time_field(out: &mut dyn WriteColor, name: &str, value: Option<f64>) {
     if let Some(secs) = value {
         let _ = write!(out, "  {name}:[{:>8.3} ms]", secs * 1000.0);
@@ -1,15 +1,24 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
 use cpal::InputCallbackInfo;
+use nix::sys::signal::{SigSet, Signal};
 use structopt::StructOpt;
 
+use bark_protocol::{ChannelCount, SampleRate, StreamFormat};
 use bark_protocol::time::{SampleDuration, Timestamp};
-use bark_protocol::packet::{self, Audio, StatsReply, PacketKind};
-use bark_protocol::types::{TimestampMicros, AudioPacketHeader, SessionId, ReceiverId, TimePhase};
-
-use crate::socket::{Socket, SocketOpt, ProtocolSocket};
+use bark_protocol::packet::{self, Audio, StatsReply, StatsRequest, PacketKind};
+use bark_protocol::types::{TimestampMicros, AudioPacketHeader, SessionId, ReceiverId, TimePhase, ProtocolInfo, Capabilities, StatsReplyFlags, StreamId};
+
+use crate::codec::{Codec, Encoded, Encoder};
+use crate::delay::AutoDelay;
+use crate::fec::FecEncoder;
+use crate::metadata::StreamMetadata;
+use crate::socket::{PeerId, Socket, SocketOpt, ProtocolSocket};
+use crate::takeover::{Takeover, TakeoverPolicy, Transition};
 use crate::{util, stats, time};
 use crate::RunError;
 
@@ -24,15 +33,144 @@ pub struct StreamOpt {
     )]
     pub device: Option<String>,
 
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_STREAM",
+    )]
+    /// Name of the stream to send, allowing several streams to share a
+    /// multicast group. Receivers select a stream with the same option
+    pub stream: Option<String>,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_TAKEOVER",
+        default_value = "newest-wins",
+    )]
+    /// What to do when another source sends the same stream:
+    /// newest-wins, first-wins, or priority. The losing source stands by
+    /// until the winner goes away
+    pub takeover: TakeoverPolicy,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_PRIORITY",
+        default_value = "0",
+    )]
+    /// Priority of this source under the priority takeover policy, higher
+    /// wins
+    pub priority: u32,
+
     #[structopt(
         long,
         env = "BARK_SOURCE_DELAY_MS",
         default_value = "20",
     )]
+    /// Delay from capturing audio to receivers playing it. With
+    /// --auto-delay, the delay to start with
     pub delay_ms: u64,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_AUTO_DELAY",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    /// Adjust the delay automatically from the stats receivers report,
+    /// keeping it as low as all receivers can play at
+    pub auto_delay: bool,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_MIN_DELAY_MS",
+        default_value = "5",
+    )]
+    /// Lowest delay set with --auto-delay
+    pub min_delay_ms: u64,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_MAX_DELAY_MS",
+        default_value = "100",
+    )]
+    /// Highest delay set with --auto-delay
+    pub max_delay_ms: u64,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_CODEC",
+        default_value = "pcm",
+    )]
+    /// Audio codec to transmit with: pcm, s16le, s24le, or opus
+    pub codec: Codec,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_SAMPLE_RATE",
+        default_value = "48000",
+    )]
+    pub sample_rate: u32,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_CHANNELS",
+        default_value = "2",
+    )]
+    pub channels: u16,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_FEC",
+        default_value = "0",
+    )]
+    /// Send a forward error correction packet after every N audio packets,
+    /// allowing receivers to recover one lost packet in each group. 0
+    /// disables
+    pub fec: u32,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_ENCRYPT",
+        default_value = "false",
+        parse(try_from_str),
+    )]
+    /// Encrypt audio with the pre-shared key set by --key
+    pub encrypt: bool,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_NAME",
+    )]
+    /// Human readable name for the stream, shown by bark stats
+    pub name: Option<String>,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_TITLE",
+    )]
+    /// Title of what is currently playing
+    pub title: Option<String>,
+
+    #[structopt(
+        long,
+        env = "BARK_SOURCE_ARTIST",
+    )]
+    /// Artist of what is currently playing
+    pub artist: Option<String>,
+
+    #[structopt(long)]
+    /// Read metadata updates from stdin, one per line in the form
+    /// name=..., title=..., or artist=...
+    pub metadata_stdin: bool,
 }
 
 pub fn run(opt: StreamOpt) -> Result<(), RunError> {
+    // block shutdown signals before any other threads are spawned, so that
+    // they are only delivered to the thread waiting for them below
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block().expect("block shutdown signals");
+
     let host = cpal::default_host();
 
     if let Some(device) = &opt.device {
@@ -42,32 +180,89 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
     let device = host.default_input_device()
         .ok_or(RunError::NoDeviceAvailable)?;
 
-    let config = util::config_for_device(&device)?;
+    let format = StreamFormat::new(SampleRate(opt.sample_rate), ChannelCount(opt.channels));
+    if !format.is_valid() {
+        return Err(RunError::InvalidStreamFormat(format));
+    }
+
+    if opt.fec > packet::MAX_FEC_GROUP_SIZE {
+        return Err(RunError::InvalidFecGroupSize(opt.fec));
+    }
+
+    let config = util::config_for_device(&device, format)?;
+
+    let auth = opt.socket.auth_key();
+
+    let cipher = match (opt.encrypt, opt.socket.cipher_key()) {
+        (true, Some(key)) => Some(key),
+        (true, None) => { return Err(RunError::EncryptWithoutKey); }
+        (false, _) => None,
+    };
 
     let socket = Socket::open(opt.socket)
         .map_err(RunError::Listen)?;
 
-    let protocol = Arc::new(ProtocolSocket::new(socket));
+    let protocol = Arc::new(ProtocolSocket::new(socket, auth));
 
-    let delay = Duration::from_millis(opt.delay_ms);
-    let delay = SampleDuration::from_std_duration_lossy(delay);
+    let delay = Arc::new(Mutex::new(AutoDelay::new(
+        Duration::from_millis(opt.delay_ms),
+        Duration::from_millis(opt.min_delay_ms),
+        Duration::from_millis(opt.max_delay_ms))));
 
     let sid = generate_session_id();
     let node = stats::node::get();
 
+    let stream_id = opt.stream.as_deref()
+        .map(StreamId::from_name)
+        .unwrap_or(StreamId::UNNAMED);
+
+    let takeover = Arc::new(Mutex::new(
+        Takeover::new(opt.takeover, sid, stream_id, opt.priority)));
+
+    // say goodbye to receivers on shutdown
+    std::thread::spawn({
+        let protocol = Arc::clone(&protocol);
+        move || {
+            crate::thread::set_name("bark/signal");
+
+            let signal = signals.wait().expect("wait for shutdown signal");
+            eprintln!("received {signal}, ending stream");
+
+            send_goodbye(&protocol, sid);
+            std::process::exit(0);
+        }
+    });
+
     let mut audio_header = AudioPacketHeader {
         sid,
+        stream: stream_id,
         seq: 1,
         pts: TimestampMicros(0),
         dts: TimestampMicros(0),
+        format,
+        priority: opt.priority,
+        _pad: 0,
     };
 
-    let mut audio_buffer = Audio::write()
+    let mut audio_buffer = Audio::write(format)
         .expect("allocate Audio packet");
 
+    let mut encoder = Encoder::new(opt.codec, format)
+        .map_err(RunError::Codec)?;
+
+    let mut fec = match opt.fec {
+        0 => None,
+        group_size => Some(FecEncoder::new(group_size)),
+    };
+
+    let history = Arc::new(Mutex::new(History::new()));
+
     let stream = device.build_input_stream(&config,
         {
             let protocol = Arc::clone(&protocol);
+            let history = Arc::clone(&history);
+            let active = takeover.lock().unwrap().active_flag();
+            let delay = delay.lock().unwrap().shared_delay();
             let mut initialized_thread = false;
             move |mut data: &[f32], _: &InputCallbackInfo| {
                 if !initialized_thread {
@@ -77,12 +272,19 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
                 }
 
                 // assert data only contains complete frames:
-                assert!(data.len() % usize::from(bark_protocol::CHANNELS) == 0);
+                assert!(data.len() % usize::from(format.channels) == 0);
+
+                let delay = Duration::from_micros(delay.load(Ordering::Relaxed));
+                let delay = SampleDuration::from_std_duration_lossy(delay, format.sample_rate);
 
-                let mut timestamp = Timestamp::from_micros_lossy(time::now()).add(delay);
+                let mut timestamp = Timestamp::from_micros_lossy(time::now(), format.sample_rate).add(delay);
+
+                // while standing by we keep packetizing audio so that seq
+                // and pts carry on as normal, but send nothing
+                let active = active.load(Ordering::Relaxed);
 
                 if audio_header.pts.0 == 0 {
-                    audio_header.pts = timestamp.to_micros_lossy();
+                    audio_header.pts = timestamp.to_micros_lossy(format.sample_rate);
                 }
 
                 while data.len() > 0 {
@@ -91,13 +293,13 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
 
                     // advance
                     timestamp = timestamp.add(written);
-                    data = &data[written.as_buffer_offset()..];
+                    data = &data[written.as_buffer_offset(format.channels)..];
 
                     // if packet buffer is full, finalize it and send off the packet:
                     if audio_buffer.valid_length() {
                         // take packet writer and replace with new
                         let audio = std::mem::replace(&mut audio_buffer,
-                            Audio::write().expect("allocate Audio packet"));
+                            Audio::write(format).expect("allocate Audio packet"));
 
                         // finalize packet
                         let audio_packet = audio.finalize(AudioPacketHeader {
@@ -105,12 +307,42 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
                             ..audio_header
                         });
 
-                        // send it
-                        protocol.broadcast(audio_packet.as_packet()).expect("broadcast");
+                        // encode and send it
+                        let encoded = encoder.encode(audio_packet).map(|packet| {
+                            match &cipher {
+                                Some(key) => packet.encrypt(key),
+                                None => packet,
+                            }
+                        });
+
+                        match encoded {
+                            Ok(_) if !active => {}
+                            Ok(packet) => {
+                                protocol.broadcast(packet.as_packet()).expect("broadcast");
+
+                                let parity = fec.as_mut()
+                                    .and_then(|fec| fec.push(packet.header(), packet.as_packet()));
+
+                                if let Some(parity) = parity {
+                                    protocol.broadcast(parity.as_packet()).expect("broadcast fec");
+                                }
+
+                                // keep hold of the packet in case a receiver
+                                // asks for it again. never wait on the lock
+                                // here, if the network thread has it the
+                                // packet just can't be retransmitted
+                                if let Ok(mut history) = history.try_lock() {
+                                    history.push(packet);
+                                }
+                            }
+                            Err(e) => {
+                                eprintln!("encode error: {e:?}");
+                            }
+                        }
 
                         // reset header for next packet:
                         audio_header.seq += 1;
-                        audio_header.pts = timestamp.to_micros_lossy();
+                        audio_header.pts = timestamp.to_micros_lossy(format.sample_rate);
                     }
                 }
 
@@ -135,41 +367,197 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
         crate::thread::set_realtime_priority();
 
         let protocol = Arc::clone(&protocol);
+        let takeover = Arc::clone(&takeover);
         move || {
-            let mut time = packet::Time::allocate()
+            let mut time = packet::Time::allocate(format)
                 .expect("allocate Time packet");
 
             // set up packet
             let data = time.data_mut();
             data.sid = sid;
             data.rid = ReceiverId::broadcast();
+            data.stream_protocol = ProtocolInfo::current();
+            data.stream = stream_id;
 
             loop {
-                time.data_mut().stream_1 = time::now();
+                let transition = takeover.lock().unwrap().tick();
+                if let Some(transition) = transition {
+                    takeover_transition(&protocol, sid, transition);
+                }
+
+                if takeover.lock().unwrap().is_active() {
+                    time.data_mut().stream_1 = time::now();
 
-                protocol.broadcast(time.as_packet())
-                    .expect("broadcast time");
+                    protocol.broadcast(time.as_packet())
+                        .expect("broadcast time");
+                }
 
                 std::thread::sleep(Duration::from_millis(200));
             }
         }
     });
 
+    let metadata = Arc::new(Mutex::new(StreamMetadata {
+        name: opt.name.clone().unwrap_or_default(),
+        title: opt.title.clone().unwrap_or_default(),
+        artist: opt.artist.clone().unwrap_or_default(),
+    }));
+
+    // periodically send metadata so that late joiners pick it up
+    std::thread::spawn({
+        let protocol = Arc::clone(&protocol);
+        let metadata = Arc::clone(&metadata);
+        let takeover = Arc::clone(&takeover);
+        move || {
+            crate::thread::set_name("bark/metadata");
+
+            loop {
+                if takeover.lock().unwrap().is_active() {
+                    let packet = metadata.lock().unwrap().to_packet(sid);
+                    let _ = protocol.broadcast(packet.as_packet());
+                }
+
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    });
+
+    if opt.auto_delay {
+        // ask receivers for their stats, replies are handled by the
+        // network thread below
+        std::thread::spawn({
+            let protocol = Arc::clone(&protocol);
+            let delay = Arc::clone(&delay);
+            let takeover = Arc::clone(&takeover);
+            move || {
+                crate::thread::set_name("bark/delay");
+
+                let request = StatsRequest::new()
+                    .expect("allocate StatsRequest packet");
+
+                loop {
+                    if takeover.lock().unwrap().is_active() {
+                        let _ = protocol.broadcast(request.as_packet());
+                    }
+
+                    std::thread::sleep(Duration::from_secs(1));
+
+                    if let Some(delay) = delay.lock().unwrap().update() {
+                        eprintln!("delay adjusted to {:.1} ms", delay.as_secs_f64() * 1000.0);
+                    }
+                }
+            }
+        });
+    }
+
+    if opt.metadata_stdin {
+        std::thread::spawn({
+            let protocol = Arc::clone(&protocol);
+            let metadata = Arc::clone(&metadata);
+            move || {
+                for line in std::io::stdin().lines() {
+                    let Ok(line) = line else { break };
+
+                    let mut metadata = metadata.lock().unwrap();
+
+                    match metadata.apply_control(&line) {
+                        Ok(()) => {
+                            // send updates straight away
+                            let packet = metadata.to_packet(sid);
+                            let _ = protocol.broadcast(packet.as_packet());
+                        }
+                        Err(e) => {
+                            eprintln!("{e}");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
     stream.play().map_err(RunError::Stream)?;
 
     crate::thread::set_name("bark/network");
     crate::thread::set_realtime_priority();
 
+    // receivers we've already warned about, so we only warn once
+    let mut incompatible_peers = HashSet::new();
+
+    let mut retransmit_limit = RetransmitLimit::new();
+
+    let mut required_capabilities = opt.codec.required_capabilities();
+    if opt.encrypt {
+        required_capabilities |= Capabilities::AUDIO_ENCRYPTED;
+    }
+
     loop {
-        let (packet, peer) = protocol.recv_from().expect("protocol.recv_from");
+        let (packet, peer, received) = protocol.recv_from().expect("protocol.recv_from");
 
-        match packet.parse() {
+        match packet.parse(protocol.auth()) {
             Some(PacketKind::Audio(audio)) => {
                 // we should only ever receive an audio packet if another
-                // stream is present. check if it should take over
-                if audio.header().sid > sid {
-                    eprintln!("Peer {peer} has taken over stream, exiting");
-                    break;
+                // source is present. check if it should take over
+                let transition = takeover.lock().unwrap().observe(audio.header());
+                if let Some(transition) = transition {
+                    eprintln!("Peer {peer} has taken over stream");
+                    takeover_transition(&protocol, sid, transition);
+                }
+            }
+            Some(PacketKind::AudioOpus(audio)) => {
+                // same as above for opus compressed streams
+                let transition = takeover.lock().unwrap().observe(audio.header());
+                if let Some(transition) = transition {
+                    eprintln!("Peer {peer} has taken over stream");
+                    takeover_transition(&protocol, sid, transition);
+                }
+            }
+            Some(PacketKind::AudioEncrypted(audio)) => {
+                // same as above for encrypted streams
+                let transition = takeover.lock().unwrap().observe(audio.header());
+                if let Some(transition) = transition {
+                    eprintln!("Peer {peer} has taken over stream");
+                    takeover_transition(&protocol, sid, transition);
+                }
+            }
+            Some(PacketKind::AudioFec(_)) => {
+                // ignore, takeover is handled by audio packets
+            }
+            Some(PacketKind::Goodbye(goodbye)) => {
+                // resume straight away if the source we stood down for
+                // has ended its stream
+                let transition = takeover.lock().unwrap().goodbye(goodbye.data().sid);
+                if let Some(transition) = transition {
+                    takeover_transition(&protocol, sid, transition);
+                }
+            }
+            Some(PacketKind::Metadata(_)) | Some(PacketKind::Control(_)) => {
+                // only of interest to receivers
+            }
+            Some(PacketKind::Nack(nack)) => {
+                let request = nack.data();
+
+                // only handle packet if it belongs to our stream:
+                if request.sid != sid {
+                    continue;
+                }
+
+                let end = request.seq.saturating_add(u64::from(request.count));
+
+                // take the packets out of the history before sending, so
+                // the audio thread isn't kept from it while we do
+                let packets = {
+                    let history = history.lock().unwrap();
+                    (request.seq..end)
+                        .filter_map(|seq| history.get(seq))
+                        .collect::<Vec<_>>()
+                };
+
+                for packet in packets {
+                    if !retransmit_limit.allow(peer) {
+                        break;
+                    }
+
+                    let _ = protocol.send_to(packet.as_packet(), peer);
                 }
             }
             Some(PacketKind::Time(mut time)) => {
@@ -180,7 +568,18 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
 
                 match time.data().phase() {
                     Some(TimePhase::ReceiverReply) => {
-                        time.data_mut().stream_3 = time::now();
+                        time.data_mut().stream_3 = received;
+
+                        let receiver = time.data().receive_protocol;
+                        if receiver.is_legacy() && incompatible_peers.insert(peer) {
+                            // receivers predating protocol versioning can't
+                            // read our audio header, whatever the codec
+                            eprintln!("warning: receiver {peer} predates protocol versioning and can't read our audio packets, upgrade the receiver");
+                        } else if !receiver.capabilities.contains(required_capabilities) && incompatible_peers.insert(peer) {
+                            let encrypted = if opt.encrypt { " with encryption" } else { "" };
+                            eprintln!("warning: receiver {peer} (protocol version {}) does not support codec {}{}",
+                                receiver.version, opt.codec, encrypted);
+                        }
 
                         protocol.send_to(time.as_packet(), peer)
                             .expect("protocol.send_to responding to time packet");
@@ -198,6 +597,9 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
 
                 let _ = protocol.send_to(reply.as_packet(), peer);
             }
+            Some(PacketKind::StatsReply(reply)) if opt.auto_delay && reply.flags().contains(StatsReplyFlags::IS_RECEIVER) => {
+                delay.lock().unwrap().observe(peer, sid, &reply);
+            }
             Some(PacketKind::StatsReply(_)) => {
                 // ignore
             }
@@ -206,8 +608,100 @@ pub fn run(opt: StreamOpt) -> Result<(), RunError> {
             }
         }
     }
+}
+
+fn takeover_transition(protocol: &Arc<ProtocolSocket>, sid: SessionId, transition: Transition) {
+    match transition {
+        Transition::StoodDown => {
+            eprintln!("standing by until stream is free");
+
+            // let receivers move on to the winning source straight away.
+            // goodbyes are repeated, don't hold up the caller meanwhile
+            let protocol = Arc::clone(protocol);
+            std::thread::spawn(move || send_goodbye(&protocol, sid));
+        }
+        Transition::Resumed => {
+            eprintln!("stream is free, sending");
+        }
+    }
+}
+
+fn send_goodbye(protocol: &ProtocolSocket, sid: SessionId) {
+    let goodbye = packet::Goodbye::new(sid)
+        .expect("allocate Goodbye packet");
+
+    // goodbye is idempotent, so send it a few times over in case of
+    // packet loss
+    for _ in 0..3 {
+        let _ = protocol.broadcast(goodbye.as_packet());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Recently sent audio packets, kept so they can be retransmitted to
+/// receivers which missed them
+struct History {
+    packets: VecDeque<Arc<Encoded>>,
+}
+
+impl History {
+    // any packet older than this would be too late to play anyway
+    const LENGTH: usize = 64;
+
+    pub fn new() -> Self {
+        History {
+            packets: VecDeque::with_capacity(Self::LENGTH),
+        }
+    }
+
+    pub fn push(&mut self, packet: Encoded) {
+        if self.packets.len() == Self::LENGTH {
+            self.packets.pop_front();
+        }
+
+        self.packets.push_back(Arc::new(packet));
+    }
+
+    pub fn get(&self, seq: u64) -> Option<Arc<Encoded>> {
+        self.packets.iter().find(|packet| packet.header().seq == seq).cloned()
+    }
+}
+
+/// Bounds the rate of retransmissions to each receiver, so a misbehaving
+/// receiver can't have us flood the network
+struct RetransmitLimit {
+    peers: HashMap<PeerId, RetransmitWindow>,
+}
+
+struct RetransmitWindow {
+    start: Instant,
+    sent: u32,
+}
+
+impl RetransmitLimit {
+    const WINDOW: Duration = Duration::from_secs(1);
+    const MAX_PER_WINDOW: u32 = 50;
+
+    pub fn new() -> Self {
+        RetransmitLimit { peers: HashMap::new() }
+    }
+
+    pub fn allow(&mut self, peer: PeerId) -> bool {
+        let now = Instant::now();
 
-    Ok(())
+        // forget about peers we haven't heard from in a while
+        self.peers.retain(|_, window| now.duration_since(window.start) < Self::WINDOW);
+
+        let window = self.peers.entry(peer)
+            .or_insert(RetransmitWindow { start: now, sent: 0 });
+
+        if window.sent >= Self::MAX_PER_WINDOW {
+            return false;
+        }
+
+        window.sent += 1;
+        true
+    }
 }
 
 pub fn generate_session_id() -> SessionId {
@@ -0,0 +1,189 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use bark_protocol::types::{AudioPacketHeader, SessionId, StreamId};
+
+/// How a stream source decides between itself and another source sending
+/// the same stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeoverPolicy {
+    /// The most recently started source wins
+    NewestWins,
+    /// A new source waits for the existing source to finish
+    FirstWins,
+    /// The source with the highest priority wins, then the newest
+    Priority,
+}
+
+#[derive(Debug)]
+pub struct UnknownTakeoverPolicy(String);
+
+impl Display for UnknownTakeoverPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown takeover policy {:?}, expected one of: newest-wins, first-wins, priority", self.0)
+    }
+}
+
+impl FromStr for TakeoverPolicy {
+    type Err = UnknownTakeoverPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest-wins" => Ok(TakeoverPolicy::NewestWins),
+            "first-wins" => Ok(TakeoverPolicy::FirstWins),
+            "priority" => Ok(TakeoverPolicy::Priority),
+            _ => Err(UnknownTakeoverPolicy(s.to_string())),
+        }
+    }
+}
+
+impl Display for TakeoverPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TakeoverPolicy::NewestWins => write!(f, "newest-wins"),
+            TakeoverPolicy::FirstWins => write!(f, "first-wins"),
+            TakeoverPolicy::Priority => write!(f, "priority"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Listening for an existing source before we start sending
+    Starting { since: Instant },
+    /// Sending audio
+    Active,
+    /// Another source has won, waiting for it to go away
+    Standby { winner: SessionId, last_seen: Instant },
+}
+
+/// Tracks whether this source should be sending audio, given the other
+/// sources sending the same stream
+pub struct Takeover {
+    policy: TakeoverPolicy,
+    sid: SessionId,
+    stream: StreamId,
+    priority: u32,
+    state: State,
+    // follows `is_active`, for the audio thread which mustn't wait on a
+    // lock held by others
+    active: Arc<AtomicBool>,
+}
+
+/// Change in whether we are sending, to be acted on by the caller
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    Resumed,
+    StoodDown,
+}
+
+impl Takeover {
+    // long enough to hear an existing source, which sends a packet every
+    // few milliseconds
+    const LISTEN: Duration = Duration::from_millis(200);
+
+    // how long the winning source must be silent before we resume. this
+    // is shorter than the default receiver session timeout, so that we're
+    // already sending again by the time receivers give up on the winner
+    const WINNER_TIMEOUT: Duration = Duration::from_millis(500);
+
+    pub fn new(policy: TakeoverPolicy, sid: SessionId, stream: StreamId, priority: u32) -> Self {
+        let state = match policy {
+            // newest source always wins, no need to wait
+            TakeoverPolicy::NewestWins => State::Active,
+            _ => State::Starting { since: Instant::now() },
+        };
+
+        let active = Arc::new(AtomicBool::new(state == State::Active));
+
+        Takeover { policy, sid, stream, priority, state, active }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == State::Active
+    }
+
+    /// Flag which is set whenever we are active, readable without locking
+    /// the takeover state
+    pub fn active_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.active)
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.active.store(self.is_active(), Ordering::Relaxed);
+    }
+
+    fn wins_against(&self, other: &AudioPacketHeader) -> bool {
+        match self.policy {
+            TakeoverPolicy::NewestWins => self.sid > other.sid,
+            TakeoverPolicy::FirstWins => self.sid < other.sid,
+            TakeoverPolicy::Priority => {
+                if self.priority != other.priority {
+                    self.priority > other.priority
+                } else {
+                    self.sid > other.sid
+                }
+            }
+        }
+    }
+
+    /// Called with audio received from other sources
+    pub fn observe(&mut self, header: &AudioPacketHeader) -> Option<Transition> {
+        if header.stream != self.stream || header.sid == self.sid {
+            // not a competing source
+            return None;
+        }
+
+        if let State::Standby { winner, last_seen } = &mut self.state {
+            if header.sid == *winner {
+                *last_seen = Instant::now();
+            }
+
+            return None;
+        }
+
+        if self.wins_against(header) {
+            return None;
+        }
+
+        let was_active = self.is_active();
+
+        self.set_state(State::Standby {
+            winner: header.sid,
+            last_seen: Instant::now(),
+        });
+
+        was_active.then_some(Transition::StoodDown)
+    }
+
+    /// Called when a source says goodbye
+    pub fn goodbye(&mut self, sid: SessionId) -> Option<Transition> {
+        match self.state {
+            State::Standby { winner, .. } if winner == sid => {
+                self.set_state(State::Active);
+                Some(Transition::Resumed)
+            }
+            _ => None,
+        }
+    }
+
+    /// Called periodically to resume once the winner has gone away
+    pub fn tick(&mut self) -> Option<Transition> {
+        let resume = match self.state {
+            State::Starting { since } => since.elapsed() >= Self::LISTEN,
+            State::Standby { last_seen, .. } => last_seen.elapsed() >= Self::WINNER_TIMEOUT,
+            State::Active => false,
+        };
+
+        if resume {
+            self.set_state(State::Active);
+            Some(Transition::Resumed)
+        } else {
+            None
+        }
+    }
+}
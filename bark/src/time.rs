@@ -1,4 +1,6 @@
-use nix::sys::time::TimeValLike;
+use std::time::Duration;
+
+use nix::sys::time::{TimeSpec, TimeValLike};
 use nix::time::ClockId;
 
 use bark_protocol::types::TimestampMicros;
@@ -12,3 +14,35 @@ pub fn now() -> TimestampMicros {
 
     TimestampMicros(micros)
 }
+
+/// Wall clock time, for timestamps compared between hosts
+pub fn realtime_now() -> TimestampMicros {
+    let timespec = nix::time::clock_gettime(ClockId::CLOCK_REALTIME)
+        .expect("clock_gettime(CLOCK_REALTIME)");
+
+    let micros = u64::try_from(timespec.num_microseconds())
+        .expect("cannot convert i64 time value to u64");
+
+    TimestampMicros(micros)
+}
+
+/// Converts a recent `CLOCK_REALTIME` timestamp, such as a kernel packet
+/// receive timestamp, to the clock `now` uses. Returns `None` if the
+/// timestamp isn't recent, which can happen if the realtime clock was
+/// stepped in the meantime
+pub fn from_realtime(timestamp: TimeSpec) -> Option<TimestampMicros> {
+    // anything older than this can't be a timestamp from a packet we've
+    // just received
+    let max_age = Duration::from_secs(1);
+
+    let realtime = nix::time::clock_gettime(ClockId::CLOCK_REALTIME).ok()?;
+    let now = now();
+
+    let age = u64::try_from((realtime - timestamp).num_microseconds()).ok()?;
+
+    if Duration::from_micros(age) > max_age {
+        return None;
+    }
+
+    now.0.checked_sub(age).map(TimestampMicros)
+}
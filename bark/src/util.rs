@@ -1,17 +1,22 @@
 use cpal::{StreamConfig, BufferSize, SupportedBufferSize, SampleFormat};
 use cpal::traits::DeviceTrait;
 
+use bark_protocol::StreamFormat;
+
 use crate::RunError;
 
 pub const SAMPLE_FORMAT: SampleFormat = SampleFormat::F32;
 
-pub fn config_for_device(device: &cpal::Device) -> Result<StreamConfig, RunError> {
+pub fn config_for_device(device: &cpal::Device, format: StreamFormat) -> Result<StreamConfig, RunError> {
     let configs = device.supported_input_configs()
         .map_err(RunError::StreamConfigs)?;
 
+    let sample_rate = cpal::SampleRate(format.sample_rate.0);
+
     let config = configs
         .filter(|config| config.sample_format() == SAMPLE_FORMAT)
-        .filter(|config| config.channels() == bark_protocol::CHANNELS.0)
+        .filter(|config| config.channels() == format.channels.0)
+        .filter(|config| config.min_sample_rate() <= sample_rate && sample_rate <= config.max_sample_rate())
         .nth(0)
         .ok_or(RunError::NoSupportedStreamConfig)?;
 
@@ -25,8 +30,8 @@ pub fn config_for_device(device: &cpal::Device) -> Result<StreamConfig, RunError
     };
 
     Ok(StreamConfig {
-        channels: bark_protocol::CHANNELS.0,
-        sample_rate: cpal::SampleRate(bark_protocol::SAMPLE_RATE.0),
+        channels: format.channels.0,
+        sample_rate,
         buffer_size: BufferSize::Fixed(buffer_size),
     })
 }